@@ -36,6 +36,38 @@ pub fn bench_parse(b: &mut Bencher) {
 }
 
 
+#[bench]
+pub fn bench_parse_combined(b: &mut Bencher) {
+    let mut parser = Rust::make_parser();
+    parser.set_combined_search(true);
+    let mut renderer = HtmlRenderer::new(&mut parser)
+        .set_mapping(&[
+            (Rust::Text,          ""),
+            (Rust::Identifier,    ""),
+            (Rust::Keyword,       "kwd"),
+            (Rust::Operator,      "opr"),
+            (Rust::FnCall,        "fun"),
+            (Rust::Punctuation,   "pun"),
+            (Rust::Lifetime,      "lif"),
+            (Rust::PrimitiveType, "typ"),
+            (Rust::Number,        "num"),
+            (Rust::Bool,          "boo"),
+            (Rust::MacroCall,     "mac"),
+            (Rust::Annotation,    "ann"),
+            (Rust::String,        "str"),
+            (Rust::StringEscape,  "esc"),
+            (Rust::Char,          "chr"),
+            (Rust::LineComment,   "com"),
+            (Rust::BlockComment,  "com"),
+            (Rust::DocComment,    "doc"),
+            (Rust::RawLiteral,    "raw"),
+        ]);
+    b.iter(|| {
+        renderer.render(STRING);
+    });
+}
+
+
 #[bench]
 pub fn bench_parse_shorter(b: &mut Bencher) {
     let mut parser = Rust::make_parser();
@@ -7,6 +7,7 @@ use std::time::SystemTime;
 use xhighlight::syntax::rust::Rust;
 use xhighlight::syntax::toml::Toml;
 use xhighlight::syntax::javascript::JS;
+use xhighlight::parse::StandardKind;
 use xhighlight::render::{Renderer, HtmlRenderer};
 
 
@@ -28,21 +29,21 @@ fn ajax<'a>(data: String) -> String {
     let tm = SystemTime::now();
 
     let s = HtmlRenderer::new(&mut JS::make_parser())
+        .set_standard_mapping(&[
+            (StandardKind::Type,        "typ"),
+            (StandardKind::Punctuation, "pun"),
+            (StandardKind::String,      "str"),
+            (StandardKind::Comment,     "com"),
+            (StandardKind::DocComment,  "com"),
+            (StandardKind::Escape,      "esc"),
+            (StandardKind::Number,      "num"),
+            (StandardKind::Function,    "fun"),
+            (StandardKind::Operator,    "opr"),
+            (StandardKind::Keyword,     "kwd"),
+            (StandardKind::Bool,        "boo"),
+            (StandardKind::Regex,       "reg"),
+        ])
         .set_mapping(&[
-            (JS::Text,           ""),
-            (JS::CommonType,     "typ"),
-            (JS::Punctuation,    "pun"),
-            (JS::String,         "str"),
-            (JS::Identifier,     ""),
-            (JS::BlockComment,   "com"),
-            (JS::LineComment,    "com"),
-            (JS::StringEscape,   "esc"),
-            (JS::Number,         "num"),
-            (JS::FnCall,         "fun"),
-            (JS::Operator,       "opr"),
-            (JS::Keyword,        "kwd"),
-            (JS::Bool,           "boo"),
-            (JS::Regex,          "reg"),
             (JS::TemplateString, "tpl"),
             (JS::TplInner,       "tpi"),
         ])
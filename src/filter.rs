@@ -0,0 +1,232 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::{
+    parse::Highlight,
+    token::Token,
+};
+
+
+/// Post-processes the tokens of a parser, e.g. to merge or split them. Filters are
+/// combined with a `Pipeline`.
+///
+/// A filter can emit any number of tokens for each token it gets, and it can hold tokens
+/// back until it gets more. The emitted tokens must be in order and must not overlap.
+pub trait TokenFilter<H: Highlight> {
+    /// Processes a token; `emit` must be called for every resulting token.
+    /// `input` is the string the tokens were parsed from.
+    fn filter(&mut self, input: &str, token: Token<H>, emit: &mut dyn FnMut(Token<H>));
+
+    /// Called after the last token, to emit the tokens that were held back
+    fn finish(&mut self, _input: &str, _emit: &mut dyn FnMut(Token<H>)) {}
+}
+
+
+/// Merges adjacent tokens with the same highlight, e.g. to produce fewer HTML elements.
+#[derive(Clone, Debug)]
+pub struct Coalesce<H: Highlight> {
+    pending: Option<Token<H>>,
+}
+
+impl<H: Highlight> Default for Coalesce<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: Highlight> Coalesce<H> {
+    pub fn new() -> Self {
+        Coalesce { pending: None }
+    }
+}
+
+impl<H: Highlight> TokenFilter<H> for Coalesce<H> {
+    fn filter(&mut self, _input: &str, token: Token<H>, emit: &mut dyn FnMut(Token<H>)) {
+        match &mut self.pending {
+            Some(p) if p.highlight == token.highlight && p.range.end == token.range.start => {
+                p.range.end = token.range.end;
+                p.end = token.end;
+            },
+            _ => if let Some(p) = self.pending.replace(token) {
+                emit(p);
+            },
+        }
+    }
+
+    fn finish(&mut self, _input: &str, emit: &mut dyn FnMut(Token<H>)) {
+        if let Some(p) = self.pending.take() {
+            emit(p);
+        }
+    }
+}
+
+
+/// Splits tokens after every line break, so no token spans several lines.
+/// The line break belongs to the token before it.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SplitLines;
+
+impl<H: Highlight> TokenFilter<H> for SplitLines {
+    fn filter(&mut self, input: &str, mut token: Token<H>, emit: &mut dyn FnMut(Token<H>)) {
+        while let Some(i) = token.text(input).find('\n') {
+            let end = token.range.start + i + 1;
+            if end == token.range.end {
+                break;
+            }
+            let rest = Token {
+                range: end .. token.range.end,
+                start: token.start.advance(&input[token.range.start .. end]),
+                ..token.clone()
+            };
+            token.range.end = end;
+            token.end = rest.start;
+            emit(std::mem::replace(&mut token, rest));
+        }
+        emit(token);
+    }
+}
+
+
+/// Changes the highlight of tokens with a certain highlight and text, e.g. to highlight
+/// the functions of a library differently:
+///
+/// ```
+/// # use xhighlight::filter::Reclassify;
+/// # use xhighlight::syntax::rust::Rust;
+/// let filter = Reclassify::new(Rust::FnCall, &[("println", Rust::MacroCall), ("dbg", Rust::MacroCall)]);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Reclassify<H: Highlight> {
+    highlight: H,
+    table: HashMap<String, H>,
+}
+
+impl<H: Highlight> Reclassify<H> {
+    /// Tokens with the highlight `highlight` get the highlight from the table if their
+    /// text is in the table.
+    pub fn new(highlight: H, table: &[(&str, H)]) -> Self {
+        Reclassify {
+            highlight,
+            table: table.iter().map(|&(text, hl)| (text.to_string(), hl)).collect(),
+        }
+    }
+}
+
+impl<H: Highlight> TokenFilter<H> for Reclassify<H> {
+    fn filter(&mut self, input: &str, mut token: Token<H>, emit: &mut dyn FnMut(Token<H>)) {
+        if token.highlight == self.highlight {
+            if let Some(&hl) = self.table.get(token.text(input)) {
+                token.highlight = hl;
+            }
+        }
+        emit(token);
+    }
+}
+
+
+/// A sequence of filters. The tokens are passed through the filters in the order
+/// in which they were added:
+///
+/// ```
+/// use xhighlight::filter::{Coalesce, Pipeline, SplitLines};
+/// use xhighlight::syntax::rust::Rust;
+///
+/// let input = "/* a\nb */ c";
+/// let mut parser = Rust::make_parser();
+/// parser.parse(input);
+/// let mut pipeline = Pipeline::new().add_filter(Coalesce::new()).add_filter(SplitLines);
+/// let tokens: Vec<_> = pipeline.apply(input, parser.tokens()).collect();
+/// assert_eq!(tokens, vec![
+///     ("/* a\n", Rust::BlockComment),
+///     ("b */", Rust::BlockComment),
+///     (" ", Rust::Text),
+///     ("c", Rust::Identifier),
+/// ]);
+/// ```
+pub struct Pipeline<'f, H: Highlight> {
+    filters: Vec<Box<dyn TokenFilter<H> + 'f>>,
+}
+
+impl<'f, H: Highlight> Default for Pipeline<'f, H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'f, H: Highlight> Pipeline<'f, H> {
+    pub fn new() -> Self {
+        Pipeline { filters: Vec::new() }
+    }
+
+    pub fn add_filter<F: TokenFilter<H> + 'f>(mut self, filter: F) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    /// Passes the tokens through the filters. `input` must be the string the tokens were
+    /// parsed from. The result can be rendered with e.g. `HtmlRenderer::render_tokens`.
+    pub fn apply<'p, 'a, I>(&'p mut self, input: &'a str, tokens: I) -> Filtered<'p, 'f, 'a, H, I::IntoIter>
+        where I: IntoIterator<Item = Token<H>>
+    {
+        Filtered {
+            pipeline: self,
+            input,
+            tokens: tokens.into_iter(),
+            queue: VecDeque::new(),
+            finished: false,
+        }
+    }
+}
+
+/// Passes a token through the filters and appends the result to `out`
+fn run<H: Highlight>(
+    filters: &mut [Box<dyn TokenFilter<H> + '_>],
+    input: &str,
+    token: Token<H>,
+    out: &mut VecDeque<Token<H>>,
+) {
+    match filters.split_first_mut() {
+        Some((first, rest)) => first.filter(input, token, &mut |t| run(rest, input, t, out)),
+        None => out.push_back(token),
+    }
+}
+
+/// An iterator over filtered tokens, returned by `Pipeline::apply`.
+pub struct Filtered<'p, 'f, 'a, H: Highlight, I: Iterator<Item = Token<H>>> {
+    pipeline: &'p mut Pipeline<'f, H>,
+    input: &'a str,
+    tokens: I,
+    /// Tokens that passed all filters
+    queue: VecDeque<Token<H>>,
+    finished: bool,
+}
+
+impl<'p, 'f, 'a, H: Highlight, I: Iterator<Item = Token<H>>> Filtered<'p, 'f, 'a, H, I> {
+    /// Returns the next token with its position
+    pub fn next_token(&mut self) -> Option<Token<H>> {
+        while self.queue.is_empty() && !self.finished {
+            let input = self.input;
+            let filters = &mut self.pipeline.filters[..];
+            match self.tokens.next() {
+                Some(token) => run(filters, input, token, &mut self.queue),
+                None => {
+                    // Filters that are finished later can still get tokens from earlier ones
+                    for i in 0 .. filters.len() {
+                        let (filter, rest) = filters[i ..].split_first_mut().unwrap();
+                        let queue = &mut self.queue;
+                        filter.finish(input, &mut |t| run(rest, input, t, queue));
+                    }
+                    self.finished = true;
+                },
+            }
+        }
+        self.queue.pop_front()
+    }
+}
+
+impl<'p, 'f, 'a, H: Highlight, I: Iterator<Item = Token<H>>> Iterator for Filtered<'p, 'f, 'a, H, I> {
+    type Item = (&'a str, H);
+    fn next(&mut self) -> Option<(&'a str, H)> {
+        let input = self.input;
+        self.next_token().map(|t| (t.text(input), t.highlight))
+    }
+}
@@ -0,0 +1,224 @@
+use std::ops::Range;
+
+use crate::parse::{Highlight, Parser, ParserState, Pattern};
+
+
+/// Keeps a highlighted document up to date while it is edited.
+///
+/// The tokens are stored per line. For every line, the lexical state at the first token
+/// boundary in the line is stored as well. After an edit, the document is parsed again
+/// from the last of these checkpoints that doesn't depend on the edited text, until the
+/// lexical state at a line after the edit is the same as before. A checkpoint depends on
+/// all the text that the parser looked at before it, including look-aheads and text that
+/// patterns scanned without matching (see `Pattern::reach`).
+///
+/// The limits of the parser (see `Parser::set_limits`) apply to every edit separately,
+/// i.e. to the text that is parsed again.
+///
+/// ```
+/// use xhighlight::incremental::Incremental;
+/// use xhighlight::syntax::rust::Rust;
+///
+/// let mut doc = Incremental::new(Rust::make_parser(), "let a = 1;\nlet b = 2;\n".to_string());
+/// let change = doc.edit(4 .. 5, "/* x");
+/// assert_eq!(doc.text(), "let /* x = 1;\nlet b = 2;\n");
+/// // The comment is never closed, so all lines are affected
+/// assert_eq!(change.new, 0 .. 3);
+/// ```
+pub struct Incremental<'a, H: Highlight, P: Pattern<H>> {
+    parser: Parser<'a, H, P>,
+    text: String,
+    lines: Vec<Line<H>>,
+}
+
+#[derive(Clone)]
+struct Line<H: Highlight> {
+    start: usize,
+    /// The offset of the first token boundary at or after the line start, relative to
+    /// the line start (it can be in a later line), and the lexical state at this boundary
+    checkpoint: (usize, ParserState<H>),
+    /// Tokens relative to the line start
+    tokens: Vec<(Range<usize>, H)>,
+    /// The end of the text that was looked at between the previous checkpoint and this one,
+    /// relative to the line start
+    reach: usize,
+}
+
+/// The lines that changed after an edit: The lines `old` (before the edit)
+/// were replaced with the lines `new`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LineChange {
+    pub old: Range<usize>,
+    pub new: Range<usize>,
+}
+
+impl<'a, H: Highlight, P: Pattern<H>> Incremental<'a, H, P> {
+    /// Creates a highlighted document. The parser is only used as a template and
+    /// doesn't need any input.
+    pub fn new(parser: Parser<'a, H, P>, text: String) -> Self {
+        let mut doc = Incremental { parser, text, lines: Vec::new() };
+        doc.relex(None, 0 .. 0, 0);
+        doc
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Returns the tokens of a line, including the line break.
+    /// Tokens that span several lines are split at the line breaks.
+    pub fn line(&self, index: usize) -> impl Iterator<Item = (&str, H)> {
+        let line = &self.lines[index];
+        let text = &self.text[line.start ..];
+        line.tokens.iter().map(move |(range, hl)| (&text[range.clone()], *hl))
+    }
+
+    /// Replaces the byte range `range` with `new_text` and highlights the affected lines again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of bounds or not on char boundaries.
+    pub fn edit(&mut self, range: Range<usize>, new_text: &str) -> LineChange {
+        self.text.replace_range(range.clone(), new_text);
+
+        // Restart at the last checkpoint where the parser hasn't looked at the edited text yet.
+        // This includes the match that the checkpoint found after it.
+        let mut reach = 0;
+        let count = self.lines
+            .iter()
+            .take_while(|line| {
+                reach = reach.max(line.start + line.reach);
+                reach <= range.start && line.start + line.checkpoint.0 < range.start
+            })
+            .count();
+        self.relex(count.checked_sub(1), range, new_text.len())
+    }
+
+    /// Parses the document from the checkpoint of line `first`, or from the start.
+    /// `removed` is the range of the old text that was replaced by `inserted` bytes.
+    fn relex(&mut self, first: Option<usize>, removed: Range<usize>, inserted: usize) -> LineChange {
+        let text = self.text.as_str();
+        let mut parser = self.parser.fork();
+        parser.track_reach();
+        parser.parse(text);
+
+        let mut lines = std::mem::take(&mut self.lines);
+        let old_count = lines.len();
+        // The old lines from `first`; lines before `first` are kept
+        let mut old_lines = lines.split_off(first.unwrap_or(0));
+
+        match first {
+            Some(first) => {
+                let line = &old_lines[0];
+                let (skip, state) = &line.checkpoint;
+                // The checkpoint is before the edit, so it's within the text
+                parser.offset(line.start + skip).unwrap();
+                parser.restore(state);
+
+                // Keep the part of the line before the checkpoint
+                lines.push(Line {
+                    start: line.start,
+                    checkpoint: line.checkpoint.clone(),
+                    tokens: line.tokens.iter().filter(|(r, _)| r.end <= *skip).cloned().collect(),
+                    reach: line.reach,
+                });
+                debug_assert_eq!(lines.len(), first + 1);
+            },
+            None => lines.push(Line {
+                start: 0,
+                checkpoint: (0, parser.snapshot()),
+                tokens: Vec::new(),
+                reach: 0,
+            }),
+        }
+        let first = first.unwrap_or(0);
+
+        let edit_end = removed.start + inserted;
+        let delta = inserted as isize - removed.len() as isize;
+        let line_end = |start: usize| text[start ..].find('\n').map(|i| start + i + 1);
+
+        let mut next_line = line_end(lines[first].start);
+        // The index of the old line where the lexical state is the same as before the edit
+        let mut converged = None;
+
+        'tokens: while let Some(token) = parser.next_token() {
+            let mut start = token.range.start;
+            // Split the token at line breaks
+            while let Some(line_start) = next_line.filter(|&s| s <= token.range.end) {
+                push_token(&mut lines, start .. line_start, token.highlight);
+                start = line_start;
+                next_line = line_end(line_start);
+
+                let checkpoint = (token.range.end - line_start, parser.snapshot());
+                let reach = parser.take_reach().saturating_sub(line_start);
+                lines.push(Line { start: line_start, checkpoint, tokens: Vec::new(), reach });
+
+                // After the edit, the text is the same as before, so if the lexical state
+                // is the same as well, the following tokens are also the same.
+                // Tokens that continue in the next line aren't compared, for simplicity.
+                let in_line = next_line.is_none_or(|end| token.range.end <= end);
+                if line_start >= edit_end && in_line {
+                    let old_start = (line_start as isize - delta) as usize;
+                    if let Ok(i) = old_lines.binary_search_by_key(&old_start, |l| l.start) {
+                        if old_lines[i].checkpoint == lines.last().unwrap().checkpoint {
+                            push_token(&mut lines, start .. token.range.end, token.highlight);
+                            converged = Some(i);
+                            break 'tokens;
+                        }
+                    }
+                }
+            }
+            push_token(&mut lines, start .. token.range.end, token.highlight);
+        }
+
+        // Lines that end before the edit and have the same tokens are unchanged
+        let unchanged = lines[first ..].windows(2)
+            .zip(&old_lines)
+            .take_while(|(new, old)| {
+                new[1].start <= removed.start && new[0].start == old.start && new[0].tokens == old.tokens
+            })
+            .count();
+
+        let change = match converged {
+            Some(i) => {
+                let new_end = lines.len() - 1;
+                let last = lines.last_mut().unwrap();
+                let skip = last.checkpoint.0;
+
+                let mut rest = old_lines.drain(i ..);
+                let old = rest.next().unwrap();
+                let (before, after): (Vec<_>, Vec<_>) = old.tokens.into_iter()
+                    .partition(|(r, _)| r.start < skip);
+                // The line is only changed if the part before the checkpoint changed
+                let changed = if before == last.tokens { 0 } else { 1 };
+                last.tokens.extend(after);
+                lines.extend(rest.map(|mut line| {
+                    line.start = (line.start as isize + delta) as usize;
+                    line
+                }));
+                LineChange {
+                    old: first + unchanged .. first + i + changed,
+                    new: first + unchanged .. new_end + changed,
+                }
+            },
+            None => LineChange {
+                old: first + unchanged .. old_count,
+                new: first + unchanged .. lines.len(),
+            },
+        };
+        self.lines = lines;
+        change
+    }
+}
+
+/// Adds a token to the last line; the range is converted to be relative to the line start
+fn push_token<H: Highlight>(lines: &mut [Line<H>], range: Range<usize>, hl: H) {
+    if range.start < range.end {
+        let line = lines.last_mut().unwrap();
+        line.tokens.push((range.start - line.start .. range.end - line.start, hl));
+    }
+}
@@ -1,7 +1,17 @@
 #![feature(test)]
+// So `#[derive(Highlight)]` can be used in this crate
+extern crate self as xhighlight;
+
 #[macro_use]
-extern crate lazy_static;
+mod macros;
 
 pub mod render;
 pub mod parse;
-pub mod syntax;
\ No newline at end of file
+pub mod token;
+pub mod syntax;
+pub mod incremental;
+pub mod stream;
+pub mod filter;
+
+#[doc(hidden)]
+pub use lazy_static::lazy_static as __lazy_static;
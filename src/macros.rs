@@ -0,0 +1,126 @@
+/// Declares the grammar of a language as a table of states and their ordered rules.
+///
+/// It generates a `Grammar` in a static, and the methods `grammar()` and `make_parser()`
+/// on the highlight type. Every rule is a pattern and the state that follows it, which is
+/// either a state (the same as `Goto(state)`), `Goto(state)`, `Push(state)` or `Pop(n)`.
+/// Patterns are converted to the pattern type with `Into`:
+///
+/// ```
+/// #[macro_use] extern crate xhighlight;
+/// use xhighlight::parse::{Highlight, RegexPat};
+///
+/// #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Highlight)]
+/// enum Lang { Text, Number, Comment }
+/// use Lang::*;
+///
+/// grammar! {
+///     Lang: RegexPat<Lang>, default Text;
+///
+///     Text => [
+///         RegexPat::regex(r"\d+", Number) => Text,
+///         RegexPat::regex(r"/\*", Comment) => Push(Comment),
+///     ],
+///     Comment => [
+///         RegexPat::shortest(r".*?\*/", Comment) => Pop(1),
+///     ],
+/// }
+///
+/// # fn main() {
+/// let tokens: Vec<_> = Lang::make_parser().parse("1 /* 2 */").collect();
+/// assert_eq!(tokens, vec![("1", Number), (" ", Text), ("/*", Comment), (" 2 */", Comment)]);
+/// # }
+/// ```
+///
+/// The states that rules transition into are checked at compile time; a state without
+/// rules is an error. The rules of a state can be extended with an iterator of
+/// `(pattern, Transition)` pairs, e.g. `Text => [...] + code_rules(Text)`; these rules
+/// are not checked. The grammar can be configured after the default state, e.g.
+/// `default Text, |grammar| grammar.set_max_depth(10);`.
+///
+/// The macro also adds a test named after the highlight type that builds the grammar,
+/// so invalid regexes are found by `cargo test`. The macro can be used for several
+/// highlight types in the same module.
+#[macro_export]
+macro_rules! grammar {
+    (
+        $hl:ident : $pat:ty, default $default:ident $(, |$grammar:ident| $config:expr)? ;
+
+        $(
+            $state:ident => [
+                $( $rule:expr => $next:ident $( ( $arg:tt ) )? ),* $(,)?
+            ] $( + $extend:expr )?
+        ),* $(,)?
+    ) => {
+        // The items are wrapped in a block, so the macro can be used more than once in a module
+        const _: () = {
+            $crate::__lazy_static! {
+                static ref GRAMMAR: $crate::parse::Grammar<$hl, $pat> = {
+                    let mut grammar = $crate::parse::Grammar::new($hl::$default);
+                    $(
+                        #[allow(unused_mut)]
+                        let mut rules: ::std::vec::Vec<($pat, $crate::parse::Transition<$hl>)> = ::std::vec![
+                            $( (::std::convert::Into::into($rule), $crate::__grammar_transition!($hl, $next $( ($arg) )?)) ),*
+                        ];
+                        $( rules.extend($extend); )?
+                        grammar.add_stack_matcher($hl::$state, rules);
+                    )*
+                    $(
+                        let $grammar = &mut grammar;
+                        $config;
+                    )?
+                    grammar
+                };
+            }
+
+            // Every state that is entered must have rules
+            const _: () = {
+                const fn has_rules(state: $hl) -> bool {
+                    matches!(state, $( $hl::$state )|*)
+                }
+                $crate::__grammar_check!(has_rules, $hl, $default);
+                $( $( $crate::__grammar_check!(has_rules, $hl, $next $( ($arg) )?); )* )*
+            };
+
+            impl $hl {
+                /// Returns the grammar, which can be shared between threads
+                pub fn grammar() -> $crate::parse::Grammar<$hl, $pat> {
+                    GRAMMAR.clone()
+                }
+
+                pub fn make_parser<'a>() -> $crate::parse::Parser<'a, $hl, $pat> {
+                    GRAMMAR.parser()
+                }
+            }
+        };
+
+        // Named after the highlight type, which is in a different namespace, so it doesn't
+        // collide with the test of another grammar in the same module
+        #[cfg(test)]
+        #[test]
+        #[allow(non_snake_case)]
+        fn $hl() {
+            // The patterns are compiled when the grammar is built
+            $hl::grammar();
+        }
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __grammar_transition {
+    ($hl:ident, Goto($state:ident)) => { $crate::parse::Transition::Goto($hl::$state) };
+    ($hl:ident, Push($state:ident)) => { $crate::parse::Transition::Push($hl::$state) };
+    ($hl:ident, Pop($n:tt)) => { $crate::parse::Transition::Pop($n) };
+    ($hl:ident, $state:ident) => { $crate::parse::Transition::Goto($hl::$state) };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __grammar_check {
+    ($has_rules:ident, $hl:ident, Pop($n:tt)) => {};
+    ($has_rules:ident, $hl:ident, Goto($state:ident)) => { $crate::__grammar_check!($has_rules, $hl, $state) };
+    ($has_rules:ident, $hl:ident, Push($state:ident)) => { $crate::__grammar_check!($has_rules, $hl, $state) };
+    ($has_rules:ident, $hl:ident, $state:ident) => {
+        assert!($has_rules($hl::$state), concat!("The state `", stringify!($state), "` has no rules"));
+    };
+}
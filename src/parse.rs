@@ -1,11 +1,19 @@
 use std::{
-    fmt::{Debug, Formatter, Error},
+    fmt::{Debug, Display, Formatter, Error},
     hash::Hash,
-    collections::HashMap
+    collections::{HashMap, HashSet, VecDeque},
+    ops::{Deref, Range},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use regex::Regex;
+use regex_automata::{hybrid::dfa::{Cache, DFA}, Anchored, Input};
+use regex_syntax::hir::{Class, Hir, HirKind, Look, Repetition};
 
+use crate::token::{Line, Position, Token};
+
+pub use xhighlight_derive::Highlight;
 
 
 /// This trait stores information about how a word should be highlighted.
@@ -23,10 +31,97 @@ use regex::Regex;
 /// }
 /// impl Highlight for MyHighlight {}
 /// ```
+///
+/// It can also be derived. The `highlight` attributes are optional; the derive also adds the
+/// constants `ALL` with all variants, and `CLASSES` with the classes for `HtmlRenderer::set_mapping`:
+///
+/// ```
+/// use xhighlight::parse::Highlight;
+///
+/// #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Highlight)]
+/// enum MyHighlight {
+///     Text,
+///     #[highlight(class = "num", kind = "number")]
+///     Number,
+///     #[highlight(class = "str", kind = "string", name = "String literal")]
+///     String,
+///     #[highlight(kind = "escape", parent = "String")]
+///     StringEscape,
+/// }
+///
+/// assert_eq!(MyHighlight::ALL.len(), 4);
+/// assert_eq!(MyHighlight::CLASSES, &[(MyHighlight::Number, "num"), (MyHighlight::String, "str")]);
+/// assert_eq!(MyHighlight::String.get_name(), "String literal");
+/// assert_eq!(MyHighlight::StringEscape.parent(), Some(MyHighlight::String));
+/// ```
+///
+/// The kinds are the variants of `StandardKind` in snake case, e.g. `doc_comment`.
 pub trait Highlight : Copy + Debug + Eq + Hash {
     fn get_name(&self) -> String {
         format!("{:?}", self)
     }
+
+    /// Returns the more general kind of this highlight, if there is one. Renderers use the
+    /// parent's style if a highlight has no style of its own, so e.g. one style for comments
+    /// can cover line, block and doc comments:
+    ///
+    /// ```
+    /// # use xhighlight::parse::Highlight;
+    /// # #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    /// # enum MyHighlight { Text, Comment, LineComment, BlockComment }
+    /// impl Highlight for MyHighlight {
+    ///     fn parent(&self) -> Option<Self> {
+    ///         match self {
+    ///             MyHighlight::LineComment | MyHighlight::BlockComment => Some(MyHighlight::Comment),
+    ///             _ => None,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// The parents must not form a cycle.
+    fn parent(&self) -> Option<Self> {
+        None
+    }
+
+    /// Returns the kind of token this is in language-independent terms, so renderers can
+    /// style all languages with the same theme (see `HtmlRenderer::set_standard_mapping`).
+    fn standard_kind(&self) -> Option<StandardKind> {
+        None
+    }
+}
+
+/// Kinds of tokens that exist in many languages, see `Highlight::standard_kind`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum StandardKind {
+    /// Text that isn't highlighted, e.g. whitespace
+    Text,
+    Keyword,
+    Identifier,
+    /// A function name, e.g. in a function call
+    Function,
+    Macro,
+    Type,
+    Lifetime,
+    Operator,
+    Punctuation,
+    Comment,
+    DocComment,
+    String,
+    /// An escape sequence in a string
+    Escape,
+    Char,
+    Number,
+    Bool,
+    /// Other literals and constants, e.g. dates
+    Constant,
+    Regex,
+    /// An annotation or attribute, e.g. `#[test]`
+    Annotation,
+    /// A key in a configuration file
+    Key,
+    /// A section header in a configuration file
+    Section,
 }
 /// Matches the pattern against a string slice. If successful,
 /// it returns the end offset of the match **in bytes**.
@@ -34,64 +129,556 @@ pub trait Highlight : Copy + Debug + Eq + Hash {
 pub trait Pattern<H: Highlight> : Debug {
     fn get_match(&self, str_pointer: &str, index: usize, next: H) -> Option<Match<H>>;
     fn highlight(&self) -> H;
+
+    /// Like `get_match`, but the pattern can look at the previous tokens.
+    /// This is used by the parser; the default implementation calls `get_match`.
+    fn get_match_in_context(&self, str_pointer: &str, index: usize, next: H, _context: &Context<H>)
+        -> Option<Match<H>>
+    {
+        self.get_match(str_pointer, index, next)
+    }
+
+    /// Returns `true` if the pattern can match without consuming any input.
+    /// This is used by `Parser::validate`.
+    fn can_match_empty(&self) -> bool {
+        false
+    }
+
+    /// Returns `true` if this pattern matches everywhere `other` matches,
+    /// so `other` is never used if it comes after this pattern in the same set.
+    /// This is used by `Parser::validate`; returning `false` is always allowed.
+    fn shadows(&self, _other: &Self) -> bool where Self: Sized {
+        false
+    }
+
+    /// Returns the bytes a non-empty match of this pattern can start with, or `None` if this
+    /// is unknown. The parser skips positions where none of the patterns can start, and
+    /// only tries the patterns that can start with the byte at the current position.
+    fn first_bytes(&self) -> Option<&ByteSet> {
+        None
+    }
+
+    /// Returns the end of the text that `get_match` looks at when it's called at `index`,
+    /// including text after the match (e.g. a look-ahead) and text that is scanned before
+    /// the pattern fails. `str_slice.len() + 1` means that it depends on where the input ends.
+    /// This is used by `Incremental` to find the tokens that an edit can change;
+    /// the default implementation assumes that the pattern looks at the whole input.
+    fn reach(&self, str_slice: &str, _index: usize) -> usize {
+        str_slice.len() + 1
+    }
+
+    /// Returns a regex that matches wherever a non-empty match of this pattern starts, or
+    /// `None` if this is unknown. It may match in more places; assertions like `^` and `\b`
+    /// are ignored. This is used by `Parser::set_combined_search`.
+    ///
+    /// The default implementation returns a character class of the `first_bytes`.
+    fn search_regex(&self) -> Option<String> {
+        self.first_bytes().and_then(ByteSet::to_regex)
+    }
+}
+
+
+
+/// A set of bytes, e.g. the bytes a pattern can start with.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ByteSet([u64; 4]);
+
+impl ByteSet {
+    pub fn new() -> Self {
+        ByteSet([0; 4])
+    }
+
+    /// A set that contains every byte
+    pub fn all() -> Self {
+        ByteSet([!0; 4])
+    }
+
+    pub fn insert(&mut self, byte: u8) {
+        self.0[byte as usize / 64] |= 1 << (byte % 64);
+    }
+
+    pub fn insert_range(&mut self, start: u8, end: u8) {
+        for byte in start ..= end {
+            self.insert(byte);
+        }
+    }
+
+    pub fn contains(&self, byte: u8) -> bool {
+        self.0[byte as usize / 64] & (1 << (byte % 64)) != 0
+    }
+
+    pub fn union(&mut self, other: &ByteSet) {
+        for (a, b) in self.0.iter_mut().zip(other.0.iter()) {
+            *a |= b;
+        }
+    }
+
+    /// Returns a character class that matches the characters starting with one of the bytes.
+    /// Non-ASCII bytes are matched by any non-ASCII character. Returns `None` if the set is empty.
+    pub fn to_regex(&self) -> Option<String> {
+        let mut class = String::new();
+        for byte in (0 ..= 0x7F).filter(|&byte| self.contains(byte)) {
+            class.push_str(&regex_syntax::escape(&char::from(byte).to_string()));
+        }
+        if (0x80 ..= 0xFF).any(|byte| self.contains(byte)) {
+            class.push_str(r"\x{80}-\x{10FFFF}");
+        }
+        if class.is_empty() {
+            None
+        } else {
+            Some(format!("[{}]", class))
+        }
+    }
 }
 
 
 
+/// Patterns based on regular expressions. `first` contains the bytes a match can start with.
 pub enum RegexPat<H: Highlight> {
-    Regex      { regex: Regex,               hl: H },
-    AtBoundary { regex: Regex,               hl: H },
-    Shortest   { regex: Regex,               hl: H },
-    LookAhead  { regex: Regex, ahead: Regex, hl: H },
-    OptionalLA { regex: Regex, ahead: Regex, hl: H, fhl: H },
+    Regex      { regex: Regex,               hl: H,         first: ByteSet },
+    AtBoundary { regex: Regex,               hl: H,         first: ByteSet },
+    Shortest   { regex: Regex,               hl: H,         first: ByteSet },
+    LookAhead  { regex: Regex, ahead: Regex, hl: H,         first: ByteSet },
+    OptionalLA { regex: Regex, ahead: Regex, hl: H, fhl: H, first: ByteSet },
+    /// Capture groups are highlighted separately; `groups` contains the indices of the groups
+    /// in `regex`, which are one more than in the original pattern
+    Captures   { regex: Regex, groups: Vec<(usize, H)>, hl: H, first: ByteSet },
+    /// Only matches if the condition is true for the previous tokens
+    After      { regex: Regex, condition: fn(&Context<H>) -> bool, hl: H, first: ByteSet },
+}
+
+/// A capture group, referenced by its index or its name. Used by `RegexPat::captures`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Group<'a> {
+    Index(usize),
+    Name(&'a str),
+}
+
+impl<'a> From<usize> for Group<'a> {
+    fn from(index: usize) -> Self {
+        Group::Index(index)
+    }
+}
+
+impl<'a> From<&'a str> for Group<'a> {
+    fn from(name: &'a str) -> Self {
+        Group::Name(name)
+    }
 }
 
 impl<H: Highlight> Debug for RegexPat<H> {
     fn fmt<'a>(&self, f: &mut Formatter<'a>) -> Result<(), Error> {
         match self {
-            RegexPat::Regex      {regex: _, hl} => write!(f, "Regex<{}>", hl.get_name()),
-            RegexPat::Shortest   {regex: _, hl} => write!(f, "Shortest<{}>", hl.get_name()),
-            RegexPat::AtBoundary {regex: _, hl} => write!(f, "AtBoundary<{}>", hl.get_name()),
-            RegexPat::LookAhead  {regex: _, ahead: _, hl} =>
+            RegexPat::Regex      {hl, ..} => write!(f, "Regex<{}>", hl.get_name()),
+            RegexPat::Shortest   {hl, ..} => write!(f, "Shortest<{}>", hl.get_name()),
+            RegexPat::AtBoundary {hl, ..} => write!(f, "AtBoundary<{}>", hl.get_name()),
+            RegexPat::LookAhead  {hl, ..} =>
                 write!(f, "LookAhead<{}>", hl.get_name()),
-            RegexPat::OptionalLA {regex: _, ahead: _, hl, fhl} =>
+            RegexPat::OptionalLA {hl, fhl, ..} =>
                 write!(f, "OptionalLA<{}, {}>", hl.get_name(), fhl.get_name()),
+            RegexPat::Captures   {hl, ..} =>
+                write!(f, "Captures<{}>", hl.get_name()),
+            RegexPat::After      {hl, ..} => write!(f, "After<{}>", hl.get_name()),
         }
     }
 }
 
 impl<H: Highlight> RegexPat<H> {
     pub fn regex(regex: &str, hl: H) -> Self {
-        RegexPat::Regex {
-            regex: Regex::new(format!("^({})", regex).as_str()).unwrap(),
-            hl
-        }
+        Self::try_regex(regex, hl).unwrap_or_else(|e| panic!("{}", e))
     }
     pub fn shortest(regex: &str, hl: H) -> Self {
-        RegexPat::Shortest {
-            regex: Regex::new(format!("^({})", regex).as_str()).unwrap(),
-            hl
-        }
+        Self::try_shortest(regex, hl).unwrap_or_else(|e| panic!("{}", e))
     }
+    /// A pattern that only matches at a word boundary, i.e. if the previous character is
+    /// not a word character (or there is none) and the first matched character is,
+    /// or vice versa. This is useful for keywords, which shouldn't be highlighted
+    /// within identifiers.
     pub fn at_boundary(regex: &str, hl: H) -> Self {
-        RegexPat::AtBoundary {
-            regex: Regex::new(format!("^({})", regex).as_str()).unwrap(),
-            hl
-        }
+        Self::try_at_boundary(regex, hl).unwrap_or_else(|e| panic!("{}", e))
     }
     pub fn look_ahead(regex: &str, ahead: &str, hl: H) -> Self {
-        RegexPat::LookAhead {
-            regex: Regex::new(format!("^({})", regex).as_str()).unwrap(),
-            ahead: Regex::new(format!("^({})", ahead).as_str()).unwrap(),
-            hl
-        }
+        Self::try_look_ahead(regex, ahead, hl).unwrap_or_else(|e| panic!("{}", e))
     }
     pub fn optional(regex: &str, ahead: &str, hl: H, fhl: H) -> Self {
-        RegexPat::OptionalLA {
-            regex: Regex::new(format!("^({})", regex).as_str()).unwrap(),
-            ahead: Regex::new(format!("^({})", ahead).as_str()).unwrap(),
+        Self::try_optional(regex, ahead, hl, fhl).unwrap_or_else(|e| panic!("{}", e))
+    }
+    /// A pattern that emits a separate token for each of the specified capture groups.
+    /// The text between the groups is highlighted with `hl`. Groups can be referenced by
+    /// their index or their name:
+    ///
+    /// ```
+    /// # use xhighlight::syntax::rust::Rust;
+    /// # use xhighlight::parse::RegexPat;
+    /// let pat = RegexPat::captures(r"(fn)\s+(?P<name>\w+)", &[(1, Rust::Keyword)], Rust::Text);
+    /// let pat = RegexPat::captures(r"(fn)\s+(?P<name>\w+)", &[("name", Rust::FnCall)], Rust::Text);
+    /// ```
+    ///
+    /// Groups that don't participate in the match or match the empty string are ignored,
+    /// as are groups that overlap with an earlier group.
+    pub fn captures<'g, G: Into<Group<'g>> + Copy>(regex: &str, groups: &[(G, H)], hl: H) -> Self {
+        Self::try_captures(regex, groups, hl).unwrap_or_else(|e| panic!("{}", e))
+    }
+    /// A pattern that only matches if `condition` returns `true` for the previous tokens.
+    /// The parser's context window must be enabled with `Parser::set_context_window`.
+    ///
+    /// ```
+    /// # use xhighlight::syntax::javascript::JS;
+    /// # use xhighlight::parse::{Context, RegexPat};
+    /// // Regex literals can't follow an identifier or a number
+    /// fn regex_allowed(context: &Context<JS>) -> bool {
+    ///     match context.last() {
+    ///         Some((_, JS::Identifier)) | Some((_, JS::Number)) => false,
+    ///         _ => true,
+    ///     }
+    /// }
+    /// let pat = RegexPat::after(r"/(\\.|[^/])+/", regex_allowed, JS::Regex);
+    /// ```
+    pub fn after(regex: &str, condition: fn(&Context<H>) -> bool, hl: H) -> Self {
+        Self::try_after(regex, condition, hl).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_regex(regex: &str, hl: H) -> Result<Self, GrammarError<H>> {
+        let regex = anchored(regex, hl)?;
+        Ok(RegexPat::Regex {
+            first: first_bytes(&regex),
+            regex,
+            hl
+        })
+    }
+    pub fn try_shortest(regex: &str, hl: H) -> Result<Self, GrammarError<H>> {
+        let regex = anchored(regex, hl)?;
+        Ok(RegexPat::Shortest {
+            first: first_bytes(&regex),
+            regex,
+            hl
+        })
+    }
+    pub fn try_at_boundary(regex: &str, hl: H) -> Result<Self, GrammarError<H>> {
+        let regex = anchored(regex, hl)?;
+        Ok(RegexPat::AtBoundary {
+            first: first_bytes(&regex),
+            regex,
+            hl
+        })
+    }
+    pub fn try_look_ahead(regex: &str, ahead: &str, hl: H) -> Result<Self, GrammarError<H>> {
+        let regex = anchored(regex, hl)?;
+        Ok(RegexPat::LookAhead {
+            first: first_bytes(&regex),
+            regex,
+            ahead: anchored(ahead, hl)?,
+            hl
+        })
+    }
+    pub fn try_optional(regex: &str, ahead: &str, hl: H, fhl: H) -> Result<Self, GrammarError<H>> {
+        let regex = anchored(regex, hl)?;
+        Ok(RegexPat::OptionalLA {
+            first: first_bytes(&regex),
+            regex,
+            ahead: anchored(ahead, hl)?,
             hl,
             fhl,
+        })
+    }
+    pub fn try_after(regex: &str, condition: fn(&Context<H>) -> bool, hl: H) -> Result<Self, GrammarError<H>> {
+        let regex = anchored(regex, hl)?;
+        Ok(RegexPat::After {
+            first: first_bytes(&regex),
+            regex,
+            condition,
+            hl,
+        })
+    }
+    pub fn try_captures<'g, G: Into<Group<'g>> + Copy>(
+        regex: &str,
+        groups: &[(G, H)],
+        hl: H,
+    ) -> Result<Self, GrammarError<H>> {
+        let compiled = anchored(regex, hl)?;
+        let groups = groups.iter()
+            .map(|&(group, group_hl)| {
+                let index = match group.into() {
+                    // Group 1 is the group added by `anchored`
+                    Group::Index(i) if i < compiled.captures_len() - 1 => Some(i + 1),
+                    Group::Index(_) => None,
+                    Group::Name(name) => compiled.capture_names().position(|n| n == Some(name)),
+                };
+                index.map(|i| (i, group_hl)).ok_or_else(|| GrammarError::UnknownGroup {
+                    group: match group.into() {
+                        Group::Index(i) => i.to_string(),
+                        Group::Name(name) => name.to_string(),
+                    },
+                    pattern: regex.to_string(),
+                    highlight: hl,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(RegexPat::Captures {
+            first: first_bytes(&compiled),
+            regex: compiled,
+            groups,
+            hl,
+        })
+    }
+}
+
+impl<H: Highlight> RegexPat<H> {
+    /// The regex that determines where the pattern ends
+    /// Returns the regex that is tried after the main regex matched, if there is one
+    fn ahead_regex(&self) -> Option<&Regex> {
+        match self {
+            RegexPat::LookAhead  { ahead, .. } => Some(ahead),
+            RegexPat::OptionalLA { ahead, .. } => Some(ahead),
+            _ => None,
+        }
+    }
+
+    fn main_regex(&self) -> &Regex {
+        match self {
+            RegexPat::Regex      { regex, .. } => regex,
+            RegexPat::Shortest   { regex, .. } => regex,
+            RegexPat::AtBoundary { regex, .. } => regex,
+            RegexPat::LookAhead  { regex, .. } => regex,
+            RegexPat::OptionalLA { regex, .. } => regex,
+            RegexPat::Captures   { regex, .. } => regex,
+            RegexPat::After      { regex, .. } => regex,
+        }
+    }
+}
+
+/// Returns the bytes matched by an anchored regex that only matches a single literal
+fn literal_bytes(hir: &Hir) -> Option<Vec<u8>> {
+    match hir.kind() {
+        HirKind::Literal(lit) => Some(lit.0.to_vec()),
+        HirKind::Capture(cap) => literal_bytes(&cap.sub),
+        HirKind::Concat(parts) => {
+            let mut bytes = Vec::new();
+            for part in parts {
+                match part.kind() {
+                    HirKind::Look(Look::Start) => {},
+                    _ => bytes.extend(literal_bytes(part)?),
+                }
+            }
+            Some(bytes)
+        },
+        _ => None,
+    }
+}
+
+/// Returns the bytes a non-empty match of the regex can start with
+fn first_bytes(regex: &Regex) -> ByteSet {
+    match regex_syntax::parse(regex.as_str()) {
+        Ok(hir) => first_bytes_of(&hir).0,
+        Err(_) => ByteSet::all(),
+    }
+}
+
+/// Returns the bytes a non-empty match can start with, and whether the match can be empty
+fn first_bytes_of(hir: &Hir) -> (ByteSet, bool) {
+    match hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => (ByteSet::new(), true),
+        HirKind::Literal(lit) => {
+            let mut set = ByteSet::new();
+            set.insert(lit.0[0]);
+            (set, false)
+        },
+        HirKind::Class(Class::Bytes(class)) => {
+            let mut set = ByteSet::new();
+            for range in class.iter() {
+                set.insert_range(range.start(), range.end());
+            }
+            (set, false)
+        },
+        HirKind::Class(Class::Unicode(class)) => {
+            let mut set = ByteSet::new();
+            let mut buf = [0; 4];
+            for range in class.iter() {
+                // The first byte of the UTF-8 encoding increases with the code point
+                let start = range.start().encode_utf8(&mut buf).as_bytes()[0];
+                let end = range.end().encode_utf8(&mut buf).as_bytes()[0];
+                set.insert_range(start, end);
+            }
+            (set, false)
+        },
+        HirKind::Repetition(rep) => {
+            let (set, empty) = first_bytes_of(&rep.sub);
+            (set, empty || rep.min == 0)
+        },
+        HirKind::Capture(cap) => first_bytes_of(&cap.sub),
+        HirKind::Concat(parts) => {
+            let mut set = ByteSet::new();
+            for part in parts {
+                let (first, empty) = first_bytes_of(part);
+                set.union(&first);
+                if !empty {
+                    return (set, false);
+                }
+            }
+            (set, true)
+        },
+        HirKind::Alternation(parts) => {
+            let mut set = ByteSet::new();
+            let mut any_empty = false;
+            for part in parts {
+                let (first, empty) = first_bytes_of(part);
+                set.union(&first);
+                any_empty |= empty;
+            }
+            (set, any_empty)
+        },
+    }
+}
+
+/// Compiles a regex that only matches at the start of the string slice
+/// Returns the end of the text an anchored regex looks at when it's matched against
+/// `&haystack[start ..]`, i.e. the position after the byte where its DFA can't continue.
+/// `haystack.len() + 1` means that the regex looked at the end of the input.
+/// Removes the assertions and capture groups from a regex, so it matches the same text
+/// (or more) regardless of the surrounding text, and it can be combined with other regexes
+fn without_looks(hir: &Hir) -> Hir {
+    match hir.kind() {
+        HirKind::Look(_) => Hir::empty(),
+        HirKind::Capture(capture) => without_looks(&capture.sub),
+        HirKind::Repetition(rep) => Hir::repetition(Repetition {
+            sub: Box::new(without_looks(&rep.sub)),
+            ..rep.clone()
+        }),
+        HirKind::Concat(hirs) => Hir::concat(hirs.iter().map(without_looks).collect()),
+        HirKind::Alternation(hirs) => Hir::alternation(hirs.iter().map(without_looks).collect()),
+        _ => hir.clone(),
+    }
+}
+
+fn regex_reach(regex: &Regex, haystack: &str, start: usize) -> usize {
+    thread_local! {
+        /// The DFAs of the regexes, or `None` if a regex can't be converted to a DFA
+        static DFAS: std::cell::RefCell<HashMap<String, Option<(DFA, Cache)>>> = Default::default();
+    }
+    let end = haystack.len() + 1;
+    DFAS.with(|dfas| {
+        let mut dfas = dfas.borrow_mut();
+        if !dfas.contains_key(regex.as_str()) {
+            let dfa = DFA::builder()
+                .configure(DFA::config().unicode_word_boundary(true))
+                .build(regex.as_str())
+                .ok()
+                .map(|dfa| {
+                    let cache = dfa.create_cache();
+                    (dfa, cache)
+                });
+            dfas.insert(regex.as_str().to_string(), dfa);
+        }
+        let (dfa, cache) = match dfas.get_mut(regex.as_str()) {
+            Some(Some(entry)) => entry,
+            _ => return end,
+        };
+        let bytes = &haystack.as_bytes()[start ..];
+        let mut state = match dfa.start_state_forward(cache, &Input::new(bytes).anchored(Anchored::Yes)) {
+            Ok(state) => state,
+            Err(_) => return end,
+        };
+        for (i, &byte) in bytes.iter().enumerate() {
+            state = match dfa.next_state(cache, state, byte) {
+                Ok(state) => state,
+                Err(_) => return end,
+            };
+            if state.is_dead() {
+                return start + i + 1;
+            }
+            if state.is_quit() {
+                return end;
+            }
+        }
+        end
+    })
+}
+
+fn anchored<H: Highlight>(regex: &str, hl: H) -> Result<Regex, GrammarError<H>> {
+    Regex::new(format!("^({})", regex).as_str())
+        .map_err(|error| GrammarError::Regex { pattern: regex.to_string(), highlight: hl, error })
+}
+
+
+
+/// The error returned when a pattern of a grammar can't be compiled, or when
+/// `Parser::try_add_matcher` rejects a pattern set. `pattern` is the regex as it was
+/// passed to the constructor, `highlight` the highlight of the pattern.
+#[derive(Clone, Debug)]
+pub enum GrammarError<H: Highlight> {
+    /// The regex crate can't compile the regex
+    Regex { pattern: String, highlight: H, error: regex::Error },
+    /// The regex has no capture group with this index or name (see `RegexPat::try_captures`)
+    UnknownGroup { group: String, pattern: String, highlight: H },
+    /// The state already has a pattern set
+    DuplicateState { state: H },
+    /// The pattern at `index` of the state's pattern set can match the empty string
+    EmptyMatch { state: H, index: usize },
+}
+
+impl<H: Highlight> GrammarError<H> {
+    /// Returns the regex that caused the error, if the error is about a single regex
+    pub fn pattern(&self) -> Option<&str> {
+        match self {
+            GrammarError::Regex { pattern, .. } | GrammarError::UnknownGroup { pattern, .. } => Some(pattern),
+            GrammarError::DuplicateState { .. } | GrammarError::EmptyMatch { .. } => None,
+        }
+    }
+
+    /// Returns the highlight of the pattern that caused the error, if the error is about a single regex
+    pub fn highlight(&self) -> Option<H> {
+        match self {
+            GrammarError::Regex { highlight, .. } | GrammarError::UnknownGroup { highlight, .. } => Some(*highlight),
+            GrammarError::DuplicateState { .. } | GrammarError::EmptyMatch { .. } => None,
+        }
+    }
+}
+
+impl<H: Highlight> Display for GrammarError<H> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            GrammarError::Regex { pattern, highlight, error } =>
+                write!(f, "Invalid pattern for {}: {}\n{}", highlight.get_name(), pattern, error),
+            GrammarError::UnknownGroup { group, pattern, highlight } =>
+                write!(f, "Invalid pattern for {}: {}\nUnknown capture group {}", highlight.get_name(), pattern, group),
+            GrammarError::DuplicateState { state } =>
+                write!(f, "State {} already has patterns", state.get_name()),
+            GrammarError::EmptyMatch { state, index } =>
+                write!(f, "Pattern {} of {} can match the empty string", index, state.get_name()),
+        }
+    }
+}
+
+impl<H: Highlight> std::error::Error for GrammarError<H> {}
+
+
+
+/// A mistake in a grammar, as reported by `Parser::validate`.
+/// Patterns are identified by the state of their pattern set and their index in the set.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GrammarIssue<H: Highlight> {
+    /// The state has a pattern set, but it can't be reached from the default state
+    Unreachable { state: H },
+    /// The pattern transitions into a state that has no pattern set
+    MissingMatcher { state: H, index: usize, next: H },
+    /// The pattern can match the empty string
+    EmptyMatch { state: H, index: usize },
+    /// The pattern is never used, because the pattern at index `by` always matches first
+    Shadowed { state: H, index: usize, by: usize },
+}
+
+impl<H: Highlight> Display for GrammarIssue<H> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            GrammarIssue::Unreachable { state } =>
+                write!(f, "State {} is unreachable", state.get_name()),
+            GrammarIssue::MissingMatcher { state, index, next } =>
+                write!(f, "Pattern {} of {} transitions into {}, which has no patterns",
+                       index, state.get_name(), next.get_name()),
+            GrammarIssue::EmptyMatch { state, index } =>
+                write!(f, "Pattern {} of {} can match the empty string", index, state.get_name()),
+            GrammarIssue::Shadowed { state, index, by } =>
+                write!(f, "Pattern {} of {} is shadowed by pattern {}", index, state.get_name(), by),
         }
     }
 }
@@ -99,179 +686,1675 @@ impl<H: Highlight> RegexPat<H> {
 impl<H: Highlight> Pattern<H> for RegexPat<H> {
     fn get_match(&self, str_slice: &str, index: usize, next: H) -> Option<Match<H>> {
         match self {
-            RegexPat::Regex { regex, hl } => {
+            RegexPat::Regex { regex, hl, .. } => {
                 regex.find(&str_slice[index .. ])
                     .map(|m| index + m.end())
                     .map(|end| {
-                        Match { highlight: *hl, next, start: index, end }
+                        Match { highlight: *hl, next, start: index, end, groups: Vec::new() }
                     })
             },
-            RegexPat::Shortest { regex, hl } => {
+            RegexPat::Shortest { regex, hl, .. } => {
                 regex.shortest_match(&str_slice[index .. ])
                     .map(|m| index + m)
-                    .map(|end| Match { highlight: *hl, next, start: index, end })
+                    .map(|end| Match { highlight: *hl, next, start: index, end, groups: Vec::new() })
             },
-            RegexPat::AtBoundary { regex, hl } => {
-                regex.find_at(str_slice, index)
-                    .filter(|m| m.start() == index)
-                    .map(|m| Match { highlight: *hl, next, start: m.start(), end: m.end() })
+            RegexPat::AtBoundary { regex, hl, .. } => {
+                if !is_word_boundary(str_slice, index) {
+                    return None;
+                }
+                regex.find(&str_slice[index .. ])
+                    .map(|m| index + m.end())
+                    .map(|end| Match { highlight: *hl, next, start: index, end, groups: Vec::new() })
             },
-            RegexPat::LookAhead { regex, ahead, hl } => {
+            RegexPat::LookAhead { regex, ahead, hl, .. } => {
                 regex.find(&str_slice[index .. ])
                     .filter(|m| ahead.is_match(&str_slice[index + m.end() .. ]))
                     .map(|m| index + m.end())
-                    .map(|end| Match { highlight: *hl, next, start: index, end })
+                    .map(|end| Match { highlight: *hl, next, start: index, end, groups: Vec::new() })
             },
-            RegexPat::OptionalLA { regex, ahead, hl, fhl } => {
+            RegexPat::OptionalLA { regex, ahead, hl, fhl, .. } => {
                 let end = regex.find(&str_slice[index .. ])?.end() + index;
                 let hl = if ahead.is_match(&str_slice[end .. ]) {
                     *hl
                 } else {
                     *fhl
                 };
-                Some(Match { highlight: hl, next, start: index, end })
+                Some(Match { highlight: hl, next, start: index, end, groups: Vec::new() })
+            },
+            RegexPat::Captures { regex, groups, hl, .. } => {
+                let captures = regex.captures(&str_slice[index .. ])?;
+                let mut found: Vec<(Range<usize>, H)> = groups.iter()
+                    .filter_map(|&(i, hl)| captures.get(i).map(|m| (index + m.start() .. index + m.end(), hl)))
+                    .filter(|(range, _)| range.start < range.end)
+                    .collect();
+                found.sort_by_key(|(range, _)| range.start);
+                let mut end = index;
+                found.retain(|(range, _)| {
+                    let keep = range.start >= end;
+                    if keep {
+                        end = range.end;
+                    }
+                    keep
+                });
+                let end = index + captures.get(0)?.end();
+                Some(Match { highlight: *hl, next, start: index, end, groups: found })
+            },
+            RegexPat::After { .. } => self.get_match_in_context(str_slice, index, next, &Context::empty()),
+        }
+    }
+    fn get_match_in_context(&self, str_slice: &str, index: usize, next: H, context: &Context<H>)
+        -> Option<Match<H>>
+    {
+        match self {
+            RegexPat::After { regex, condition, hl, .. } => {
+                if !condition(context) {
+                    return None;
+                }
+                regex.find(&str_slice[index .. ])
+                    .map(|m| index + m.end())
+                    .map(|end| Match { highlight: *hl, next, start: index, end, groups: Vec::new() })
             },
+            _ => self.get_match(str_slice, index, next),
         }
     }
     fn highlight(&self) -> H {
         match self {
-            RegexPat::Regex      { regex: _,           hl         } => *hl,
-            RegexPat::Shortest   { regex: _,           hl         } => *hl,
-            RegexPat::AtBoundary { regex: _,           hl         } => *hl,
-            RegexPat::LookAhead  { regex: _, ahead: _, hl         } => *hl,
-            RegexPat::OptionalLA { regex: _, ahead: _, hl, fhl: _ } => *hl,
+            RegexPat::Regex      { hl, .. } => *hl,
+            RegexPat::Shortest   { hl, .. } => *hl,
+            RegexPat::AtBoundary { hl, .. } => *hl,
+            RegexPat::LookAhead  { hl, .. } => *hl,
+            RegexPat::OptionalLA { hl, .. } => *hl,
+            RegexPat::Captures   { hl, .. } => *hl,
+            RegexPat::After      { hl, .. } => *hl,
+        }
+    }
+    fn can_match_empty(&self) -> bool {
+        regex_syntax::parse(self.main_regex().as_str())
+            .map(|hir| hir.properties().minimum_len() == Some(0))
+            .unwrap_or(false)
+    }
+    fn shadows(&self, other: &Self) -> bool {
+        // The only condition of these patterns is that the main regex matches
+        let unconditional = match self {
+            RegexPat::Regex { .. } | RegexPat::Shortest { .. } | RegexPat::OptionalLA { .. }
+                | RegexPat::Captures { .. } => true,
+            RegexPat::AtBoundary { .. } | RegexPat::LookAhead { .. } | RegexPat::After { .. } => false,
+        };
+        if !unconditional {
+            return false;
+        }
+        if self.main_regex().as_str() == other.main_regex().as_str() {
+            return true;
+        }
+
+        // If `other` only matches a literal and this pattern matches a non-empty prefix of it
+        // without looking at the surrounding text, it matches wherever `other` matches.
+        // Empty matches are ignored by the parser, so they don't count
+        let own = match regex_syntax::parse(self.main_regex().as_str()) {
+            Ok(hir) => hir,
+            Err(_) => return false,
+        };
+        if !own.properties().look_set().remove(Look::Start).is_empty() {
+            return false;
+        }
+        let literal = regex_syntax::parse(other.main_regex().as_str())
+            .ok()
+            .and_then(|hir| literal_bytes(&hir));
+        match literal {
+            Some(literal) => match std::str::from_utf8(&literal) {
+                Ok(literal) => self.main_regex().find(literal).is_some_and(|m| m.end() > 0),
+                Err(_) => false,
+            },
+            None => false,
+        }
+    }
+    fn first_bytes(&self) -> Option<&ByteSet> {
+        match self {
+            RegexPat::Regex      { first, .. } => Some(first),
+            RegexPat::Shortest   { first, .. } => Some(first),
+            RegexPat::AtBoundary { first, .. } => Some(first),
+            RegexPat::LookAhead  { first, .. } => Some(first),
+            RegexPat::OptionalLA { first, .. } => Some(first),
+            RegexPat::Captures   { first, .. } => Some(first),
+            RegexPat::After      { first, .. } => Some(first),
+        }
+    }
+    fn search_regex(&self) -> Option<String> {
+        Some(self.main_regex().as_str().to_string())
+    }
+    fn reach(&self, str_slice: &str, index: usize) -> usize {
+        let reach = regex_reach(self.main_regex(), str_slice, index);
+        // The look-ahead is only tried after a match
+        match self.ahead_regex() {
+            Some(ahead) => match self.main_regex().find(&str_slice[index ..]) {
+                Some(m) => reach.max(regex_reach(ahead, str_slice, index + m.end())),
+                None => reach,
+            },
+            None => reach,
         }
     }
 }
 
 
 
-#[derive(Debug)]
-pub struct Match<H: Highlight> {
-    pub highlight: H,
-    pub next: H,
-    pub start: usize,
-    pub end: usize,
+/// A pattern that calls a function to scan the input, for constructs that are hard
+/// to describe with a regex. The function gets the string and the current offset,
+/// and returns the end offset of the match **in bytes**:
+///
+/// ```
+/// # use xhighlight::syntax::rust::Rust;
+/// # use xhighlight::parse::FnPat;
+/// // A run of `#`, e.g. the markdown heading level
+/// let pat = FnPat::new(|s: &str, i: usize| {
+///     let len = s[i ..].bytes().take_while(|&b| b == b'#').count();
+///     if len > 0 { Some(i + len) } else { None }
+/// }, Rust::Punctuation).set_first_bytes(b"#");
+/// ```
+///
+/// Matches that are empty or don't end at a char boundary are ignored.
+pub struct FnPat<H: Highlight> {
+    scan: ScanFn<H>,
+    hl: H,
+    first: Option<ByteSet>,
+    reach: Option<ReachFn>,
 }
 
+/// Returns the end of the match and, unless the default is used, its highlight
+type ScanFn<H> = Box<dyn Fn(&str, usize) -> Option<(usize, Option<H>)> + Send + Sync>;
 
+/// Returns the end of the text the scan function looks at, see `Pattern::reach`
+type ReachFn = Box<dyn Fn(&str, usize) -> usize + Send + Sync>;
 
-#[derive(Debug)]
-pub struct Matcher<'a, H: Highlight, P: Pattern<H>> {
-    expressions: &'a Vec<(P, H)>,   // pattern, highlight for next pattern
-}
-
-impl<'a, H: Highlight, P: Pattern<H>> Matcher<'a, H, P> {
-    pub fn new(expressions: &'a Vec<(P, H)>) -> Self {
-        Matcher { expressions }
+impl<H: Highlight> FnPat<H> {
+    pub fn new<F>(scan: F, hl: H) -> Self
+        where F: Fn(&str, usize) -> Option<usize> + Send + Sync + 'static
+    {
+        let scan = move |s: &str, index| scan(s, index).map(|end| (end, None));
+        FnPat { scan: Box::new(scan), hl, first: None, reach: None }
     }
-    pub fn next_match(&self, str_slice: &str, mut index: usize) -> Option<Match<H>> {
-        let len = str_slice.len();
-        // Iterate through str_slice while it's not empty
-        while index < len {
-            // pat: pattern; hl: highlight for next pattern
-            for (pat, hl) in self.expressions.iter() {
-                let m = pat.get_match(str_slice, index, *hl);
-                if m.is_some() {
-                    return m;
-                }
-            }
 
-            //TODO maybe use .chars()
+    /// Like `new`, but the function also returns the highlight of the match.
+    /// `hl` is only returned by `Pattern::highlight`.
+    pub fn with_highlight<F>(scan: F, hl: H) -> Self
+        where F: Fn(&str, usize) -> Option<(usize, H)> + Send + Sync + 'static
+    {
+        let scan = move |s: &str, index| scan(s, index).map(|(end, hl)| (end, Some(hl)));
+        FnPat { scan: Box::new(scan), hl, first: None, reach: None }
+    }
 
-            // Figure out if the next char is 1, 2, 3 or 4 bytes long
-            // This is safe since str_slice can't be empty
-            let next_byte = unsafe { str_slice.as_bytes().get_unchecked(index) };
-            let ch_len = char_len(*next_byte);
-            index += ch_len;
+    /// Sets the bytes a match can start with, so the function isn't called
+    /// at other positions. See `Pattern::first_bytes`.
+    pub fn set_first_bytes(mut self, bytes: &[u8]) -> Self {
+        let mut first = ByteSet::new();
+        for &b in bytes {
+            first.insert(b);
         }
+        self.first = Some(first);
+        self
+    }
 
-        None
+    /// Sets a function that returns the end of the text the scan function looks at,
+    /// see `Pattern::reach`. Without it, `Incremental` assumes that the scan function
+    /// looks at the whole input.
+    pub fn set_reach<F>(mut self, reach: F) -> Self
+        where F: Fn(&str, usize) -> usize + Send + Sync + 'static
+    {
+        self.reach = Some(Box::new(reach));
+        self
+    }
+}
+
+impl<H: Highlight> Debug for FnPat<H> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "FnPat<{}>", self.hl.get_name())
+    }
+}
+
+impl<H: Highlight> Pattern<H> for FnPat<H> {
+    fn get_match(&self, str_slice: &str, index: usize, next: H) -> Option<Match<H>> {
+        (self.scan)(str_slice, index)
+            .filter(|&(end, _)| end > index && str_slice.is_char_boundary(end))
+            .map(|(end, hl)| {
+                Match { highlight: hl.unwrap_or(self.hl), next, start: index, end, groups: Vec::new() }
+            })
+    }
+    fn highlight(&self) -> H {
+        self.hl
+    }
+    fn first_bytes(&self) -> Option<&ByteSet> {
+        self.first.as_ref()
+    }
+    fn reach(&self, str_slice: &str, index: usize) -> usize {
+        match &self.reach {
+            Some(reach) => reach(str_slice, index),
+            None => str_slice.len() + 1,
+        }
     }
 }
 
 
 
-pub struct Parser<'a, H: Highlight, P: Pattern<H>> {
-    input: &'a str,
-    offset: usize,
+/// A pattern that matches whole words from a list, e.g. keywords. The words must only
+/// contain word characters. Instead of trying a regex, the word at the current position
+/// is looked up in a hash set, so long lists are cheap:
+///
+/// ```
+/// # use xhighlight::syntax::javascript::JS;
+/// # use xhighlight::parse::KeywordPat;
+/// let pat = KeywordPat::ignore_case(&["select", "from", "where"], JS::Keyword);
+/// // `$` is part of identifiers, so `$if` isn't a keyword
+/// let pat = KeywordPat::new(&["if", "else"], JS::Keyword)
+///     .set_word_chars(|c| c == '$' || regex_syntax::is_word_character(c));
+/// ```
+pub struct KeywordPat<H: Highlight> {
+    /// The words; they are lowercase if the case is ignored
+    words: HashSet<String>,
+    ignore_case: bool,
+    word_chars: fn(char) -> bool,
     hl: H,
-    buffered: Option<Match<H>>,
-    matchers: HashMap<H, Matcher<'a, H, P>>,
+    first: ByteSet,
 }
 
-impl<'a, H: Highlight, P: Pattern<H>> Parser<'a, H, P> {
-    pub fn new(default_hl: H) -> Self {
-        Parser {
-            input: "",
-            offset: 0,
-            hl: default_hl,
-            buffered: None,
-            matchers: HashMap::new(),
+impl<H: Highlight> KeywordPat<H> {
+    pub fn new(words: &[&str], hl: H) -> Self {
+        let mut first = ByteSet::new();
+        for word in words {
+            if let Some(&b) = word.as_bytes().first() {
+                first.insert(b);
+            }
+        }
+        KeywordPat {
+            words: words.iter().map(|w| w.to_string()).collect(),
+            ignore_case: false,
+            word_chars: regex_syntax::is_word_character,
+            hl,
+            first,
         }
     }
 
-    pub fn add_matcher(&mut self, before: H, expressions: &'a Vec<(P, H)>) -> &mut Self {
-        self.matchers.insert(before, Matcher::new(expressions));
-        self
+    /// Like `new`, but the words are matched case-insensitively
+    pub fn ignore_case(words: &[&str], hl: H) -> Self {
+        let mut first = ByteSet::new();
+        for word in words {
+            if let Some(&b) = word.as_bytes().first() {
+                first.insert(b.to_ascii_lowercase());
+                first.insert(b.to_ascii_uppercase());
+            }
+        }
+        // Some non-ASCII characters are lowercased to ASCII, e.g. the Kelvin sign
+        first.insert_range(0xC0, 0xFF);
+        KeywordPat {
+            words: words.iter().map(|w| w.to_lowercase()).collect(),
+            ignore_case: true,
+            word_chars: regex_syntax::is_word_character,
+            hl,
+            first,
+        }
     }
 
-    pub fn parse(&mut self, input: &'a str) -> &mut Self {
-        self.input = input;
-        self.offset = 0;
-        self.buffered = None;
+    /// Sets the characters that belong to a word. The default is `\w`.
+    /// A word only matches if it isn't preceded or followed by such a character.
+    pub fn set_word_chars(mut self, word_chars: fn(char) -> bool) -> Self {
+        self.word_chars = word_chars;
         self
     }
+}
 
-    pub fn offset(&mut self, offset: usize) -> Result<(), &str> {
-        if offset < self.input.len() {
-            self.offset = offset;
-            Ok(())
+impl<H: Highlight> Debug for KeywordPat<H> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "Keyword<{}>", self.hl.get_name())
+    }
+}
+
+impl<H: Highlight> Pattern<H> for KeywordPat<H> {
+    fn get_match(&self, str_slice: &str, index: usize, next: H) -> Option<Match<H>> {
+        if str_slice[.. index].chars().next_back().is_some_and(self.word_chars) {
+            return None;
+        }
+        let rest = &str_slice[index ..];
+        let len = rest.find(|c| !(self.word_chars)(c)).unwrap_or(rest.len());
+        let word = &rest[.. len];
+        let found = if self.ignore_case && word.bytes().any(|b| b >= 0x80 || b.is_ascii_uppercase()) {
+            self.words.contains(&word.to_lowercase())
+        } else {
+            self.words.contains(word)
+        };
+        if len == 0 || !found {
+            return None;
+        }
+        Some(Match { highlight: self.hl, next, start: index, end: index + len, groups: Vec::new() })
+    }
+    fn highlight(&self) -> H {
+        self.hl
+    }
+    fn first_bytes(&self) -> Option<&ByteSet> {
+        Some(&self.first)
+    }
+    fn search_regex(&self) -> Option<String> {
+        if self.ignore_case {
+            // Lowercasing doesn't work like case folding in regexes
+            return self.first.to_regex();
+        }
+        let words: Vec<_> = self.words.iter().map(|word| regex_syntax::escape(word)).collect();
+        Some(words.join("|"))
+    }
+    fn reach(&self, str_slice: &str, index: usize) -> usize {
+        // The word ends before the first character that isn't a word character
+        match str_slice[index ..].char_indices().find(|&(_, c)| !(self.word_chars)(c)) {
+            Some((i, c)) => index + i + c.len_utf8(),
+            None => str_slice.len() + 1,
+        }
+    }
+}
+
+
+
+/// A `RegexPat`, `KeywordPat` or `FnPat`, so they can be used in the same pattern set.
+/// They all convert into this with `into()`.
+pub enum AnyPat<H: Highlight> {
+    Regex(RegexPat<H>),
+    Keyword(KeywordPat<H>),
+    Fn(FnPat<H>),
+}
+
+impl<H: Highlight> Debug for AnyPat<H> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            AnyPat::Regex(pat) => pat.fmt(f),
+            AnyPat::Keyword(pat) => pat.fmt(f),
+            AnyPat::Fn(pat) => pat.fmt(f),
+        }
+    }
+}
+
+impl<H: Highlight> From<RegexPat<H>> for AnyPat<H> {
+    fn from(pat: RegexPat<H>) -> Self {
+        AnyPat::Regex(pat)
+    }
+}
+
+impl<H: Highlight> From<KeywordPat<H>> for AnyPat<H> {
+    fn from(pat: KeywordPat<H>) -> Self {
+        AnyPat::Keyword(pat)
+    }
+}
+
+impl<H: Highlight> From<FnPat<H>> for AnyPat<H> {
+    fn from(pat: FnPat<H>) -> Self {
+        AnyPat::Fn(pat)
+    }
+}
+
+impl<H: Highlight> Pattern<H> for AnyPat<H> {
+    fn get_match(&self, str_slice: &str, index: usize, next: H) -> Option<Match<H>> {
+        match self {
+            AnyPat::Regex(pat) => pat.get_match(str_slice, index, next),
+            AnyPat::Keyword(pat) => pat.get_match(str_slice, index, next),
+            AnyPat::Fn(pat) => pat.get_match(str_slice, index, next),
+        }
+    }
+    fn get_match_in_context(&self, str_slice: &str, index: usize, next: H, context: &Context<H>)
+        -> Option<Match<H>>
+    {
+        match self {
+            AnyPat::Regex(pat) => pat.get_match_in_context(str_slice, index, next, context),
+            AnyPat::Keyword(pat) => pat.get_match_in_context(str_slice, index, next, context),
+            AnyPat::Fn(pat) => pat.get_match_in_context(str_slice, index, next, context),
+        }
+    }
+    fn highlight(&self) -> H {
+        match self {
+            AnyPat::Regex(pat) => pat.highlight(),
+            AnyPat::Keyword(pat) => pat.highlight(),
+            AnyPat::Fn(pat) => pat.highlight(),
+        }
+    }
+    fn can_match_empty(&self) -> bool {
+        match self {
+            AnyPat::Regex(pat) => pat.can_match_empty(),
+            AnyPat::Keyword(pat) => pat.can_match_empty(),
+            AnyPat::Fn(pat) => pat.can_match_empty(),
+        }
+    }
+    fn shadows(&self, other: &Self) -> bool {
+        match (self, other) {
+            (AnyPat::Regex(pat), AnyPat::Regex(other)) => pat.shadows(other),
+            _ => false,
+        }
+    }
+    fn first_bytes(&self) -> Option<&ByteSet> {
+        match self {
+            AnyPat::Regex(pat) => pat.first_bytes(),
+            AnyPat::Keyword(pat) => pat.first_bytes(),
+            AnyPat::Fn(pat) => pat.first_bytes(),
+        }
+    }
+    fn search_regex(&self) -> Option<String> {
+        match self {
+            AnyPat::Regex(pat) => pat.search_regex(),
+            AnyPat::Keyword(pat) => pat.search_regex(),
+            AnyPat::Fn(pat) => pat.search_regex(),
+        }
+    }
+    fn reach(&self, str_slice: &str, index: usize) -> usize {
+        match self {
+            AnyPat::Regex(pat) => pat.reach(str_slice, index),
+            AnyPat::Keyword(pat) => pat.reach(str_slice, index),
+            AnyPat::Fn(pat) => pat.reach(str_slice, index),
+        }
+    }
+}
+
+
+
+/// The tokens before the position where a pattern is tried, see `Pattern::get_match_in_context`.
+///
+/// Only the last few tokens are available, as configured with `Parser::set_context_window`.
+/// Tokens that only contain whitespace and tokens with a skipped highlight
+/// (e.g. comments) are not included.
+#[derive(Copy, Clone)]
+pub struct Context<'c, H: Highlight> {
+    window: Option<&'c VecDeque<(H, String)>>,
+    skip: &'c [H],
+    /// The text between the last token and the current position,
+    /// which becomes a token of the current state
+    gap: Option<(&'c str, H)>,
+}
+
+impl<'c, H: Highlight> Context<'c, H> {
+    /// A context without previous tokens
+    pub fn empty() -> Self {
+        Context { window: None, skip: &[], gap: None }
+    }
+
+    /// Returns the `n`-th previous token (`0` is the last one), if it is in the context window.
+    pub fn previous(&self, n: usize) -> Option<(&str, H)> {
+        let window = self.window?;
+        let gap = self.gap.filter(|&(text, hl)| !text.trim().is_empty() && !self.skip.contains(&hl));
+        let n = match (gap, n) {
+            (Some(gap), 0) => return Some(gap),
+            (Some(_), n) => n - 1,
+            (None, n) => n,
+        };
+        let (hl, text) = window.get(window.len().checked_sub(n + 1)?)?;
+        Some((text, *hl))
+    }
+
+    /// Returns the last token
+    pub fn last(&self) -> Option<(&str, H)> {
+        self.previous(0)
+    }
+}
+
+#[derive(Debug)]
+pub struct Match<H: Highlight> {
+    pub highlight: H,
+    pub next: H,
+    pub start: usize,
+    pub end: usize,
+    /// Parts of the match that are highlighted differently, ordered and not overlapping.
+    /// The rest of the match is highlighted with `highlight`.
+    pub groups: Vec<(Range<usize>, H)>,
+}
+
+
+
+/// Describes how the state changes after a pattern matched.
+///
+/// The parser has a stack of states, so nested constructs (e.g. nested block comments)
+/// can be parsed. The current state is not part of the stack.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Transition<H: Highlight> {
+    /// Replace the current state
+    Goto(H),
+    /// Push the current state on the stack, then enter the new state
+    Push(H),
+    /// Pop the specified number of states from the stack; the last one becomes the
+    /// current state. If the stack is too small, the default state becomes the current state.
+    Pop(usize),
+}
+
+impl<H: Highlight> Transition<H> {
+    /// Returns the state that is entered, unless the state is popped from the stack
+    pub fn target(&self) -> Option<H> {
+        match *self {
+            Transition::Goto(hl) | Transition::Push(hl) => Some(hl),
+            Transition::Pop(_) => None,
+        }
+    }
+}
+
+impl<H: Highlight> From<H> for Transition<H> {
+    fn from(hl: H) -> Self {
+        Transition::Goto(hl)
+    }
+}
+
+
+
+/// A pattern set that is either borrowed or owned by a `Grammar`
+#[derive(Debug)]
+enum Patterns<'a, T> {
+    Borrowed(&'a [T]),
+    Shared(Arc<Vec<T>>),
+}
+
+impl<'a, T> Deref for Patterns<'a, T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        match self {
+            Patterns::Borrowed(e) => e,
+            Patterns::Shared(e) => e,
+        }
+    }
+}
+
+// Implemented manually, because T doesn't need to be Clone
+impl<'a, T> Clone for Patterns<'a, T> {
+    fn clone(&self) -> Self {
+        match self {
+            Patterns::Borrowed(e) => Patterns::Borrowed(e),
+            Patterns::Shared(e) => Patterns::Shared(e.clone()),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Expressions<'a, H: Highlight, P: Pattern<H>> {
+    Plain(Patterns<'a, (P, H)>),              // pattern, highlight for next pattern
+    Stack(Patterns<'a, (P, Transition<H>)>),  // pattern, transition after the pattern
+}
+
+impl<'a, H: Highlight, P: Pattern<H>> Clone for Expressions<'a, H, P> {
+    fn clone(&self) -> Self {
+        match self {
+            Expressions::Plain(e) => Expressions::Plain(e.clone()),
+            Expressions::Stack(e) => Expressions::Stack(e.clone()),
+        }
+    }
+}
+
+/// Finds the patterns of a set that can match at a position, using the first byte
+#[derive(Debug)]
+pub struct Matcher<'a, H: Highlight, P: Pattern<H>> {
+    expressions: Expressions<'a, H, P>,
+    /// The bytes any of the patterns can start with
+    first: ByteSet,
+    /// The search regexes of all patterns combined, see `Parser::set_combined_search`
+    search: Option<Regex>,
+}
+
+impl<'a, H: Highlight, P: Pattern<H>> Clone for Matcher<'a, H, P> {
+    fn clone(&self) -> Self {
+        Matcher { expressions: self.expressions.clone(), first: self.first, search: self.search.clone() }
+    }
+}
+
+impl<'a, H: Highlight, P: Pattern<H>> Matcher<'a, H, P> {
+    pub fn new(expressions: &'a [(P, H)]) -> Self {
+        Self::with_expressions(Expressions::Plain(Patterns::Borrowed(expressions)))
+    }
+    pub fn with_transitions(expressions: &'a [(P, Transition<H>)]) -> Self {
+        Self::with_expressions(Expressions::Stack(Patterns::Borrowed(expressions)))
+    }
+
+    fn with_expressions(expressions: Expressions<'a, H, P>) -> Self {
+        let mut matcher = Matcher { expressions, first: ByteSet::new(), search: None };
+        let mut first = ByteSet::new();
+        for (pat, _) in matcher.iter() {
+            match pat.first_bytes() {
+                Some(bytes) => first.union(bytes),
+                None => first = ByteSet::all(),
+            }
+        }
+        matcher.first = first;
+        matcher
+    }
+
+    /// Combines the search regexes of the patterns into one regex, which finds the leftmost
+    /// position where any of them matches, so no pattern can match before that position.
+    /// Returns `None` if a pattern has no search regex, or if the combined regex can match
+    /// the empty string, since it would match at every position.
+    fn combined_search(&self) -> Option<Regex> {
+        let alternatives = self.iter()
+            .map(|(pat, _)| {
+                let hir = regex_syntax::parse(&pat.search_regex()?).ok()?;
+                Some(without_looks(&hir))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let hir = Hir::alternation(alternatives);
+        if hir.properties().minimum_len().is_none_or(|len| len == 0) {
+            return None;
+        }
+        Regex::new(&hir.to_string()).ok()
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.expressions {
+            Expressions::Plain(e) => e.len(),
+            Expressions::Stack(e) => e.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the pattern at the specified index, and the transition after the pattern
+    pub fn get(&self, index: usize) -> (&P, Transition<H>) {
+        match &self.expressions {
+            Expressions::Plain(e) => (&e[index].0, Transition::Goto(e[index].1)),
+            Expressions::Stack(e) => (&e[index].0, e[index].1),
+        }
+    }
+
+    pub fn iter<'m>(&'m self) -> impl Iterator<Item = (&'m P, Transition<H>)> + 'm {
+        (0 .. self.len()).map(move |i| self.get(i))
+    }
+
+    /// Returns the first match at or after `index`.
+    ///
+    /// Matches that are empty or don't start at the tested index are ignored, so every match
+    /// returned by this function consumes input. This guarantees that the parser can't get
+    /// stuck, even if a pattern can match the empty string.
+    ///
+    /// Positions where no pattern can start are skipped, and at every other position,
+    /// only the patterns that can start with the byte at this position are tried, in order,
+    /// so the first pattern that matches wins. The positions are found with the bytes the
+    /// patterns can start with, or with the combined search (see `Parser::set_combined_search`).
+    pub fn next_match(&self, str_slice: &str, index: usize) -> Option<(Match<H>, Transition<H>)> {
+        self.find(str_slice, index, &Context::empty(), &mut Budget::default(), None)
+            .map(|(m, transition, _)| (m, transition))
+    }
+
+    /// Like `next_match`, but the patterns can look at the previous tokens, and the index
+    /// of the pattern is returned as well. If the match isn't at `start`, the text before it
+    /// becomes a token, so it's added to the context. Every pattern that is tried takes
+    /// a step from the budget; if it is used up, `None` is returned. If `reach` is given,
+    /// it's extended to the end of the text that was looked at, see `Pattern::reach`.
+    fn find(&self, str_slice: &str, start: usize, context: &Context<H>, budget: &mut Budget,
+            mut reach: Option<&mut usize>) -> Option<(Match<H>, Transition<H>, usize)>
+    {
+        let mut index = start;
+        let bytes = str_slice.as_bytes();
+        let len = str_slice.len();
+        // Iterate through str_slice while it's not empty
+        while index < len {
+            // Jump to the next position where a pattern can start
+            let next = match &self.search {
+                Some(search) => search.find_at(str_slice, index).map(|m| m.start()),
+                // Skip UTF-8 continuation bytes
+                None => bytes[index ..].iter()
+                    .position(|&b| self.first.contains(b) && b & 0xC0 != 0x80)
+                    .map(|skip| index + skip),
+            };
+            match next {
+                Some(next) => index = next,
+                None => break,
+            }
+            extend_reach(&mut reach, index + 1);
+            let byte = bytes[index];
+            let context = Context {
+                gap: context.gap.map(|(_, state)| (&str_slice[start .. index], state)),
+                ..*context
+            };
+
+            for (i, (pat, transition)) in self.iter().enumerate() {
+                if !pat.first_bytes().is_none_or(|first| first.contains(byte)) {
+                    continue;
+                }
+                if !budget.step() {
+                    extend_reach(&mut reach, len + 1);
+                    return None;
+                }
+                if reach.is_some() {
+                    extend_reach(&mut reach, pat.reach(str_slice, index));
+                }
+                let next = transition.target().unwrap_or_else(|| pat.highlight());
+                let m = pat.get_match_in_context(str_slice, index, next, &context);
+                if let Some(m) = m {
+                    if m.start == index && m.end > index {
+                        return Some((m, transition, i));
+                    }
+                }
+            }
+
+            // Go to the next char. The index is at a char boundary, so this is never `None`
+            index += char_len(byte).unwrap_or(1);
+        }
+
+        extend_reach(&mut reach, len + 1);
+        None
+    }
+}
+
+/// Extends the reach to `end`, if it's tracked
+fn extend_reach(reach: &mut Option<&mut usize>, end: usize) {
+    if let Some(reach) = reach {
+        **reach = (**reach).max(end);
+    }
+}
+
+
+
+/// Limits for parsing untrusted input, see `Parser::set_limits`. `None` means unlimited.
+///
+/// The limits apply to one call of `Parser::parse`. A `TokenStream` shares them between all
+/// chunks of its input, so they apply to the whole stream, while `Incremental` parses
+/// with new limits for every edit.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum length of the input in bytes. Longer input isn't parsed at all.
+    pub max_input: Option<usize>,
+    /// The maximum number of steps. A step is an attempt to match a pattern at a position.
+    pub max_steps: Option<usize>,
+    /// The maximum time after `Parser::parse` is called
+    pub time_limit: Option<Duration>,
+}
+
+/// The limit that was exceeded, see `Parser::limit_exceeded`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Limit {
+    Input,
+    Steps,
+    Time,
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            Limit::Input => write!(f, "The input is too long"),
+            Limit::Steps => write!(f, "The maximum number of steps was exceeded"),
+            Limit::Time => write!(f, "The time limit was exceeded"),
+        }
+    }
+}
+
+/// The steps and the time the parser has left
+#[derive(Clone, Debug)]
+pub(crate) struct Budget {
+    steps: usize,
+    deadline: Option<Instant>,
+    exceeded: Option<Limit>,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Budget { steps: usize::MAX, deadline: None, exceeded: None }
+    }
+}
+
+impl Budget {
+    fn new(limits: &Limits, input_len: usize) -> Self {
+        Budget {
+            steps: limits.max_steps.unwrap_or(usize::MAX),
+            deadline: limits.time_limit.map(|limit| Instant::now() + limit),
+            exceeded: limits.max_input.filter(|&max| input_len > max).map(|_| Limit::Input),
+        }
+    }
+
+    pub(crate) fn exceeded(&self) -> Option<Limit> {
+        self.exceeded
+    }
+
+    /// Takes a step; returns `false` if a limit is exceeded
+    fn step(&mut self) -> bool {
+        if self.exceeded.is_some() {
+            return false;
+        }
+        if self.steps == 0 {
+            self.exceeded = Some(Limit::Steps);
+            return false;
+        }
+        self.steps -= 1;
+        // Getting the time is relatively expensive, so it isn't checked at every step
+        !self.steps.is_multiple_of(1024) || self.check_time()
+    }
+
+    /// Returns `false` if a limit is exceeded
+    fn check_time(&mut self) -> bool {
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            self.exceeded = Some(Limit::Time);
+        }
+        self.exceeded.is_none()
+    }
+}
+
+
+
+/// What the parser does when it enters a state that has no pattern set.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Fallback {
+    /// Emit the rest of the input as a single token of the current state
+    Remainder,
+    /// Go back to the default state and continue parsing
+    DefaultState,
+    /// Stop parsing; `Parser::try_next_match` returns a `ParseError`
+    Fail,
+}
+
+/// The error returned by `Parser::try_next_match`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ParseError<H: Highlight> {
+    /// The parser entered a state without a pattern set at the specified offset
+    MissingMatcher { state: H, offset: usize },
+}
+
+impl<H: Highlight> Display for ParseError<H> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            ParseError::MissingMatcher { state, offset } =>
+                write!(f, "State {} at offset {} has no patterns", state.get_name(), offset),
+        }
+    }
+}
+
+impl<H: Highlight> std::error::Error for ParseError<H> {}
+
+
+
+/// A match that hasn't been emitted yet, because it was found after a gap
+/// or consists of several tokens. `start` is where the rest of the match starts.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Buffered<H: Highlight> {
+    highlight: H,
+    start: usize,
+    end: usize,
+    transition: Transition<H>,
+    /// The state in which the match was found
+    state: H,
+    /// The capture groups that weren't emitted yet
+    groups: Vec<(Range<usize>, H)>,
+    /// The index of the pattern in the pattern set of `state`
+    pattern: usize,
+}
+
+/// A token recorded by `Parser::set_trace`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceEntry<H: Highlight> {
+    /// The byte range of the token in the input
+    pub range: Range<usize>,
+    /// The text of the token
+    pub text: String,
+    pub highlight: H,
+    /// The state in which the token was found
+    pub state: H,
+    /// The index of the pattern in the pattern set of `state`, or `None` if the token
+    /// is text between matches
+    pub pattern: Option<usize>,
+    /// The `Debug` output of the pattern, e.g. `Regex<Keyword>`
+    pub label: Option<String>,
+    /// The transition that was applied after the token. If a pattern emits several tokens
+    /// (see `RegexPat::captures`), it is applied after the last one.
+    pub transition: Option<Transition<H>>,
+}
+
+/// A snapshot of the lexical state of a `Parser`, created with `Parser::snapshot`.
+///
+/// It contains the current state and the state stack, but no reference to the input or the
+/// grammar. Offsets are stored relative to the parser's offset, so a snapshot taken at the
+/// start of a line can be restored on a different parser at the start of the same line,
+/// e.g. when highlighting a document line by line.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ParserState<H: Highlight> {
+    hl: H,
+    stack: Vec<H>,
+    buffered: Option<Buffered<H>>,
+    context: VecDeque<(H, String)>,
+}
+
+impl<H: Highlight> ParserState<H> {
+    /// Returns the current state of the parser
+    pub fn state(&self) -> H {
+        self.hl
+    }
+
+}
+
+
+
+/// The pattern sets and settings of a grammar
+struct Rules<'a, H: Highlight, P: Pattern<H>> {
+    default_hl: H,
+    max_depth: usize,
+    fallback: Fallback,
+    matchers: HashMap<H, Matcher<'a, H, P>>,
+    /// The number of tokens in the context window, and the highlights that aren't included
+    context_size: usize,
+    context_skip: Vec<H>,
+    limits: Limits,
+    combined_search: bool,
+}
+
+// Implemented manually, because P doesn't need to be Clone
+impl<'a, H: Highlight, P: Pattern<H>> Clone for Rules<'a, H, P> {
+    fn clone(&self) -> Self {
+        Rules {
+            default_hl: self.default_hl,
+            max_depth: self.max_depth,
+            fallback: self.fallback,
+            matchers: self.matchers.clone(),
+            context_size: self.context_size,
+            context_skip: self.context_skip.clone(),
+            limits: self.limits,
+            combined_search: self.combined_search,
+        }
+    }
+}
+
+impl<'a, H: Highlight, P: Pattern<H>> Rules<'a, H, P> {
+    fn new(default_hl: H) -> Self {
+        Rules {
+            default_hl,
+            max_depth: 64,
+            fallback: Fallback::Remainder,
+            matchers: HashMap::new(),
+            context_size: 0,
+            context_skip: Vec::new(),
+            limits: Limits::default(),
+            combined_search: false,
+        }
+    }
+
+    fn add_matcher(&mut self, before: H, mut matcher: Matcher<'a, H, P>) {
+        if self.combined_search {
+            matcher.search = matcher.combined_search();
+        }
+        self.matchers.insert(before, matcher);
+    }
+
+    fn set_combined_search(&mut self, enabled: bool) {
+        self.combined_search = enabled;
+        for matcher in self.matchers.values_mut() {
+            matcher.search = if enabled { matcher.combined_search() } else { None };
+        }
+    }
+
+    fn validate(&self) -> Result<(), Vec<GrammarIssue<H>>> {
+        let mut issues = Vec::new();
+
+        // Find reachable states, in the order in which they are discovered
+        let mut reachable = vec![self.default_hl];
+        let mut i = 0;
+        while i < reachable.len() {
+            if let Some(matcher) = self.matchers.get(&reachable[i]) {
+                for next in matcher.iter().filter_map(|(_, t)| t.target()) {
+                    if !reachable.contains(&next) {
+                        reachable.push(next);
+                    }
+                }
+            }
+            i += 1;
+        }
+        let unreachable = self.matchers.keys().filter(|state| !reachable.contains(state));
+        let states = reachable.iter().chain(unreachable);
+
+        for &state in states {
+            let matcher = match self.matchers.get(&state) {
+                Some(matcher) => matcher,
+                None => continue,
+            };
+            if !reachable.contains(&state) {
+                issues.push(GrammarIssue::Unreachable { state });
+            }
+            for (index, (pat, transition)) in matcher.iter().enumerate() {
+                if let Some(next) = transition.target() {
+                    if !self.matchers.contains_key(&next) {
+                        issues.push(GrammarIssue::MissingMatcher { state, index, next });
+                    }
+                }
+                if pat.can_match_empty() {
+                    issues.push(GrammarIssue::EmptyMatch { state, index });
+                }
+                let earlier = matcher.iter().take(index)
+                    .position(|(earlier, _)| earlier.shadows(pat));
+                if let Some(by) = earlier {
+                    issues.push(GrammarIssue::Shadowed { state, index, by });
+                }
+            }
+        }
+
+        if issues.is_empty() {
+            Ok(())
         } else {
+            Err(issues)
+        }
+    }
+}
+
+
+
+/// A grammar that owns its pattern sets. Parsers for individual inputs are created with
+/// `Grammar::parser`, which is cheap, because the pattern sets aren't copied.
+///
+/// A `Grammar` can be cloned cheaply, and it is `Send` and `Sync` if the patterns are,
+/// so it can be shared between threads, e.g. in the managed state of a web server.
+///
+/// ```
+/// use xhighlight::parse::{Grammar, RegexPat};
+/// use xhighlight::syntax::rust::Rust;
+///
+/// let mut grammar = Grammar::new(Rust::Text);
+/// grammar.add_matcher(Rust::Text, vec![
+///     (RegexPat::regex(r"\d+", Rust::Number), Rust::Text),
+/// ]);
+/// let tokens: Vec<_> = grammar.parser().parse("a 42").collect();
+/// assert_eq!(tokens, vec![("a ", Rust::Text), ("42", Rust::Number)]);
+/// ```
+pub struct Grammar<H: Highlight + 'static, P: Pattern<H> + 'static> {
+    rules: Arc<Rules<'static, H, P>>,
+}
+
+impl<H: Highlight + 'static, P: Pattern<H> + 'static> Clone for Grammar<H, P> {
+    fn clone(&self) -> Self {
+        Grammar { rules: self.rules.clone() }
+    }
+}
+
+impl<H: Highlight + 'static, P: Pattern<H> + 'static> Grammar<H, P> {
+    pub fn new(default_hl: H) -> Self {
+        Grammar { rules: Arc::new(Rules::new(default_hl)) }
+    }
+
+    /// Creates a parser for this grammar. The parser can parse input of any lifetime.
+    pub fn parser<'a>(&self) -> Parser<'a, H, P> {
+        Parser::with_rules(self.rules.clone())
+    }
+
+    pub fn add_matcher(&mut self, before: H, expressions: Vec<(P, H)>) -> &mut Self {
+        let matcher = Matcher::with_expressions(Expressions::Plain(Patterns::Shared(Arc::new(expressions))));
+        Arc::make_mut(&mut self.rules).add_matcher(before, matcher);
+        self
+    }
+
+    /// See `Parser::add_stack_matcher`
+    pub fn add_stack_matcher(&mut self, before: H, expressions: Vec<(P, Transition<H>)>) -> &mut Self {
+        let matcher = Matcher::with_expressions(Expressions::Stack(Patterns::Shared(Arc::new(expressions))));
+        Arc::make_mut(&mut self.rules).add_matcher(before, matcher);
+        self
+    }
+
+    /// Like `add_matcher`, but borrows a pattern set, e.g. one in a `lazy_static`
+    pub fn add_static_matcher(&mut self, before: H, expressions: &'static [(P, H)]) -> &mut Self {
+        Arc::make_mut(&mut self.rules).add_matcher(before, Matcher::new(expressions));
+        self
+    }
+
+    /// Like `add_stack_matcher`, but borrows a pattern set, e.g. one in a `lazy_static`
+    pub fn add_static_stack_matcher(
+        &mut self,
+        before: H,
+        expressions: &'static [(P, Transition<H>)],
+    ) -> &mut Self {
+        Arc::make_mut(&mut self.rules).add_matcher(before, Matcher::with_transitions(expressions));
+        self
+    }
+
+    /// See `Parser::set_max_depth`
+    pub fn set_max_depth(&mut self, max_depth: usize) -> &mut Self {
+        Arc::make_mut(&mut self.rules).max_depth = max_depth;
+        self
+    }
+
+    /// See `Parser::set_fallback`
+    pub fn set_fallback(&mut self, fallback: Fallback) -> &mut Self {
+        Arc::make_mut(&mut self.rules).fallback = fallback;
+        self
+    }
+
+    /// See `Parser::set_context_window`
+    pub fn set_context_window(&mut self, size: usize, skip: &[H]) -> &mut Self {
+        let rules = Arc::make_mut(&mut self.rules);
+        rules.context_size = size;
+        rules.context_skip = skip.to_vec();
+        self
+    }
+
+    /// See `Parser::set_limits`
+    pub fn set_limits(&mut self, limits: Limits) -> &mut Self {
+        Arc::make_mut(&mut self.rules).limits = limits;
+        self
+    }
+
+    /// See `Parser::set_combined_search`
+    pub fn set_combined_search(&mut self, enabled: bool) -> &mut Self {
+        Arc::make_mut(&mut self.rules).set_combined_search(enabled);
+        self
+    }
+
+    /// See `Parser::validate`
+    pub fn validate(&self) -> Result<(), Vec<GrammarIssue<H>>> {
+        self.rules.validate()
+    }
+}
+
+
+
+pub struct Parser<'a, H: Highlight, P: Pattern<H>> {
+    input: &'a str,
+    offset: usize,
+    position: Position,
+    hl: H,
+    /// States to return to; the current state is `hl`
+    stack: Vec<H>,
+    buffered: Option<Buffered<H>>,
+    /// The last tokens, see `Context`
+    context: VecDeque<(H, String)>,
+    /// The emitted tokens, if tracing is enabled
+    trace: Option<Vec<TraceEntry<H>>>,
+    /// The end of the text that was looked at since `take_reach`, if it's tracked
+    reach: Option<usize>,
+    budget: Budget,
+    rules: Arc<Rules<'a, H, P>>,
+}
+
+impl<'a, H: Highlight, P: Pattern<H>> Parser<'a, H, P> {
+    pub fn new(default_hl: H) -> Self {
+        Self::with_rules(Arc::new(Rules::new(default_hl)))
+    }
+
+    fn with_rules(rules: Arc<Rules<'a, H, P>>) -> Self {
+        Parser {
+            input: "",
+            offset: 0,
+            position: Position::default(),
+            hl: rules.default_hl,
+            stack: Vec::new(),
+            buffered: None,
+            context: VecDeque::new(),
+            trace: None,
+            reach: None,
+            budget: Budget::default(),
+            rules,
+        }
+    }
+
+    /// Creates a parser with the same pattern sets and settings, but without input.
+    /// Since the input can have a shorter lifetime, this can be used to parse strings
+    /// that don't live as long as the pattern sets.
+    pub fn fork<'b>(&self) -> Parser<'b, H, P> where 'a: 'b {
+        Parser::with_rules(self.rules.clone())
+    }
+
+    pub fn add_matcher(&mut self, before: H, expressions: &'a [(P, H)]) -> &mut Self {
+        Arc::make_mut(&mut self.rules).add_matcher(before, Matcher::new(expressions));
+        self
+    }
+
+    /// Adds a pattern set whose patterns can push states on the stack or pop them.
+    /// A state's pattern set can be added either with `add_matcher` or with this function.
+    pub fn add_stack_matcher(&mut self, before: H, expressions: &'a [(P, Transition<H>)]) -> &mut Self {
+        Arc::make_mut(&mut self.rules).add_matcher(before, Matcher::with_transitions(expressions));
+        self
+    }
+
+    /// Sets the maximum size of the state stack. If the stack is full,
+    /// `Transition::Push` replaces the current state without pushing it.
+    /// The default is 64.
+    pub fn set_max_depth(&mut self, max_depth: usize) -> &mut Self {
+        Arc::make_mut(&mut self.rules).max_depth = max_depth;
+        self
+    }
+
+    /// Enables the context window, so patterns can look at the last `size` tokens
+    /// (see `Pattern::get_match_in_context`). Tokens that only contain whitespace, and tokens
+    /// with one of the `skip` highlights (e.g. comments) are not included.
+    /// The default size is 0.
+    pub fn set_context_window(&mut self, size: usize, skip: &[H]) -> &mut Self {
+        let rules = Arc::make_mut(&mut self.rules);
+        rules.context_size = size;
+        rules.context_skip = skip.to_vec();
+        self
+    }
+
+    /// Like `add_matcher`, but checks the pattern set first, for grammars that aren't
+    /// hard-coded. The pattern set isn't added if the state already has one,
+    /// or if one of the patterns can match the empty string.
+    pub fn try_add_matcher(&mut self, before: H, expressions: &'a [(P, H)]) -> Result<(), GrammarError<H>> {
+        if self.rules.matchers.contains_key(&before) {
+            return Err(GrammarError::DuplicateState { state: before });
+        }
+        if let Some(index) = expressions.iter().position(|(pat, _)| pat.can_match_empty()) {
+            return Err(GrammarError::EmptyMatch { state: before, index });
+        }
+        Arc::make_mut(&mut self.rules).add_matcher(before, Matcher::new(expressions));
+        Ok(())
+    }
+
+    /// Checks the grammar for mistakes that would lead to wrong or incomplete output:
+    /// States that can't be reached from the default state, transitions into states
+    /// without a pattern set, patterns that can match the empty string, and patterns
+    /// that are shadowed by an earlier pattern in the same set.
+    pub fn validate(&self) -> Result<(), Vec<GrammarIssue<H>>> {
+        self.rules.validate()
+    }
+
+    pub fn parse(&mut self, input: &'a str) -> &mut Self {
+        self.input = input;
+        self.offset = 0;
+        self.position = Position::default();
+        self.hl = self.rules.default_hl;
+        self.stack.clear();
+        self.buffered = None;
+        self.context.clear();
+        if let Some(trace) = &mut self.trace {
+            trace.clear();
+        }
+        if let Some(reach) = &mut self.reach {
+            *reach = 0;
+        }
+        self.budget = Budget::new(&self.rules.limits, input.len());
+        self
+    }
+
+    /// Parses input that might not be valid UTF-8. The valid parts are parsed as usual,
+    /// and every invalid sequence becomes a `ByteToken::Invalid`. Patterns can't match
+    /// across invalid sequences, but the state of the parser is kept.
+    ///
+    /// ```
+    /// use xhighlight::parse::ByteToken;
+    /// use xhighlight::syntax::rust::Rust;
+    ///
+    /// let mut parser = Rust::make_parser();
+    /// let tokens: Vec<_> = parser.parse_bytes(b"\"a\xFFc\"").collect();
+    /// assert_eq!(tokens, vec![
+    ///     ByteToken::Text("\"", Rust::String),
+    ///     ByteToken::Text("a", Rust::String),
+    ///     ByteToken::Invalid(b"\xFF"),
+    ///     ByteToken::Text("c", Rust::String),
+    ///     ByteToken::Text("\"", Rust::String),
+    /// ]);
+    /// ```
+    pub fn parse_bytes<'p>(&'p mut self, input: &'a [u8]) -> ByteTokens<'p, 'a, H, P> {
+        self.parse("");
+        self.budget = Budget::new(&self.rules.limits, input.len());
+        ByteTokens { parser: self, chunks: input.utf8_chunks(), invalid: None, start: 0, traced: 0 }
+    }
+
+    pub fn offset(&mut self, offset: usize) -> Result<(), &str> {
+        if offset >= self.input.len() {
             Err("Index out of bounds")
+        } else if !self.input.is_char_boundary(offset) {
+            Err("Index is not at a char boundary")
+        } else {
+            self.offset = offset;
+            self.position = Position::default().advance(&self.input[.. offset]);
+            self.buffered = None;
+            self.context.clear();
+            Ok(())
+        }
+    }
+
+    /// Returns the lexical state of the parser at the current offset.
+    pub fn snapshot(&self) -> ParserState<H> {
+        ParserState {
+            hl: self.hl,
+            stack: self.stack.clone(),
+            buffered: self.buffered.as_ref().map(|b| Buffered {
+                start: b.start - self.offset,
+                end: b.end - self.offset,
+                groups: b.groups.iter()
+                    .map(|(r, hl)| (r.start - self.offset .. r.end - self.offset, *hl))
+                    .collect(),
+                ..b.clone()
+            }),
+            context: self.context.clone(),
+        }
+    }
+
+    /// Restores a snapshot at the current offset. The parser should use the same grammar as
+    /// the parser that created the snapshot. If the snapshot contains a match that doesn't fit
+    /// into the input, the match is discarded and searched again.
+    pub fn restore(&mut self, state: &ParserState<H>) -> &mut Self {
+        self.hl = state.hl;
+        self.stack.clone_from(&state.stack);
+        self.context.clone_from(&state.context);
+        self.buffered = state.buffered.as_ref()
+            .map(|b| Buffered {
+                start: b.start + self.offset,
+                end: b.end + self.offset,
+                groups: b.groups.iter()
+                    .map(|(r, hl)| (r.start + self.offset .. r.end + self.offset, *hl))
+                    .collect(),
+                ..b.clone()
+            })
+            .filter(|b| self.input.get(b.start .. b.end).is_some());
+        self
+    }
+
+    /// Enables or disables tracing. While tracing is enabled, the parser records for every
+    /// token which pattern matched it, see `trace`. This is useful to debug a grammar.
+    pub fn set_trace(&mut self, enabled: bool) -> &mut Self {
+        self.trace = if enabled { Some(Vec::new()) } else { None };
+        self
+    }
+
+    /// Enables tracking the end of the text that the parser looks at, see `take_reach`
+    pub(crate) fn track_reach(&mut self) {
+        self.reach = Some(0);
+    }
+
+    /// Returns the end of the text that was looked at since the last call or since `parse`
+    /// was called, e.g. when searching for the tokens that were emitted since then.
+    /// `input.len() + 1` means that it depends on where the input ends.
+    pub(crate) fn take_reach(&mut self) -> usize {
+        self.reach.as_mut().map_or(0, std::mem::take)
+    }
+
+    /// Returns the tokens emitted since tracing was enabled or `parse` was called
+    pub fn trace(&self) -> &[TraceEntry<H>] {
+        self.trace.as_deref().unwrap_or(&[])
+    }
+
+    /// Returns the trace as a table with a row for every token
+    pub fn trace_table(&self) -> String {
+        let rows: Vec<[String; 6]> = self.trace().iter()
+            .map(|e| {
+                let mut text = format!("{:?}", e.text);
+                if text.chars().count() > 40 {
+                    text = text.chars().take(37).chain("...".chars()).collect();
+                }
+                [
+                    format!("{}..{}", e.range.start, e.range.end),
+                    text,
+                    e.highlight.get_name(),
+                    e.state.get_name(),
+                    match (&e.pattern, &e.label) {
+                        (Some(i), Some(label)) => format!("{} {}", i, label),
+                        (Some(i), None) => i.to_string(),
+                        _ => "-".to_string(),
+                    },
+                    e.transition.map_or("-".to_string(), |t| format!("{:?}", t)),
+                ]
+            })
+            .collect();
+        let header = ["Range", "Text", "Highlight", "State", "Pattern", "Transition"].map(String::from);
+
+        let mut widths = [0; 6];
+        for row in std::iter::once(&header).chain(&rows) {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        let mut table = String::new();
+        for row in std::iter::once(&header).chain(&rows) {
+            let line: Vec<_> = row.iter().zip(&widths)
+                .map(|(cell, &width)| format!("{:width$}", cell, width = width))
+                .collect();
+            table.push_str(line.join(" | ").trim_end());
+            table.push('\n');
+        }
+        table
+    }
+
+    /// Sets what happens when the parser enters a state without a pattern set.
+    /// The default is `Fallback::Remainder`.
+    pub fn set_fallback(&mut self, fallback: Fallback) -> &mut Self {
+        Arc::make_mut(&mut self.rules).fallback = fallback;
+        self
+    }
+
+    /// Limits the work done for an input, e.g. if it comes from an untrusted source.
+    /// When a limit is exceeded, the rest of the input is emitted as a single token of the
+    /// default state, and `limit_exceeded` returns the limit. The limits take effect
+    /// when `parse` is called. By default, there are no limits.
+    pub fn set_limits(&mut self, limits: Limits) -> &mut Self {
+        Arc::make_mut(&mut self.rules).limits = limits;
+        self
+    }
+
+    /// Sets whether to find the positions where a pattern can start with one regex that
+    /// combines the `search_regex` of all patterns of a state, instead of by the bytes they can
+    /// start with. The patterns are still tried in order at these positions, so the tokens
+    /// are the same. States with a pattern without a search regex use the bytes.
+    /// Whether this is faster depends on the grammar and the input; `cargo bench` compares
+    /// both for Rust. By default, it is disabled.
+    pub fn set_combined_search(&mut self, enabled: bool) -> &mut Self {
+        Arc::make_mut(&mut self.rules).set_combined_search(enabled);
+        self
+    }
+
+    /// Returns the limit that was exceeded while parsing the current input, if any
+    pub fn limit_exceeded(&self) -> Option<Limit> {
+        self.budget.exceeded
+    }
+
+    /// Returns the steps and the time that are left for the current input
+    pub(crate) fn budget(&self) -> &Budget {
+        &self.budget
+    }
+
+    /// Continues with a budget that was left by another parser, instead of the one that
+    /// `parse` created, so several inputs share the limits. `input_len` is their total length.
+    pub(crate) fn continue_budget(&mut self, budget: &Budget, input_len: usize) {
+        self.budget = budget.clone();
+        if self.rules.limits.max_input.is_some_and(|max| input_len > max) {
+            self.budget.exceeded = Some(Limit::Input);
         }
     }
 
     pub fn next_match(&mut self) -> Option<(&'a str, H)> {
-        match &self.buffered {
-            Some(m) => {
-                let string = &self.input[m.start .. m.end];
-                let hl = m.highlight;
-
-                self.hl = m.next;
-                self.offset = m.end;
-                self.buffered = None;
-                Some((string, hl))
-            },
+        self.try_next_match().unwrap_or(None)
+    }
+
+    /// Like `next_match`, but returns an error if the parser enters a state without
+    /// a pattern set and the fallback is `Fallback::Fail`.
+    pub fn try_next_match(&mut self) -> Result<Option<(&'a str, H)>, ParseError<H>> {
+        let input = self.input;
+        Ok(self.try_next_token()?.map(|t| (t.text(input), t.highlight)))
+    }
+
+    /// Returns the next token with its position, or `None` at the end of the input.
+    pub fn next_token(&mut self) -> Option<Token<H>> {
+        self.try_next_token().unwrap_or(None)
+    }
+
+    /// Like `next_token`, but returns an error if the parser enters a state without
+    /// a pattern set and the fallback is `Fallback::Fail`.
+    pub fn try_next_token(&mut self) -> Result<Option<Token<H>>, ParseError<H>> {
+        match self.buffered.take() {
+            Some(b) => Ok(Some(self.emit_buffered(b))),
             None => {
-                let matcher = self.matchers.get(&self.hl)?;
-                if let Some(m) = matcher.next_match(self.input, self.offset) {
+                if !self.budget.check_time() {
+                    return Ok(self.remainder(self.rules.default_hl));
+                }
+                let matcher = match self.rules.matchers.get(&self.hl) {
+                    Some(matcher) => matcher,
+                    None => return self.missing_matcher(),
+                };
+                let context = Context {
+                    window: (self.rules.context_size > 0).then_some(&self.context),
+                    skip: &self.rules.context_skip,
+                    gap: Some(("", self.hl)),
+                };
+                let found = matcher.find(self.input, self.offset, &context, &mut self.budget, self.reach.as_mut());
+                if let Some((m, transition, pattern)) = found {
+                    let state = self.hl;
+                    let buffered = Buffered {
+                        highlight: m.highlight,
+                        start: m.start,
+                        end: m.end,
+                        transition,
+                        state,
+                        groups: m.groups,
+                        pattern,
+                    };
                     if m.start > self.offset {
-                        let string = &self.input[self.offset .. m.start];
-                        let hl = self.hl;
-
-                        self.hl = m.highlight;
-                        self.buffered = Some(m);
-                        Some((string, hl))
+                        let token = self.emit(m.start, state, state);
+                        self.add_to_trace(&token, None);
+                        self.buffered = Some(buffered);
+                        Ok(Some(token))
                     } else {
-                        let string = &self.input[m.start .. m.end];
-                        let hl = m.highlight;
-
-                        self.offset = m.end;
-                        self.hl = m.next;
-                        self.buffered = None;
-                        Some((string, hl))
+                        Ok(Some(self.emit_buffered(buffered)))
                     }
-                } else if self.offset < self.input.len() {
-                    let res = (&self.input[self.offset .. ], self.hl);
-                    self.offset = self.input.len();
-                    Some(res)
+                } else if self.budget.exceeded.is_some() {
+                    Ok(self.remainder(self.rules.default_hl))
                 } else {
-                    None
+                    Ok(self.remainder(self.hl))
                 }
             }
         }
     }
+
+    /// Returns an iterator over the remaining tokens, including their positions.
+    pub fn tokens<'p>(&'p mut self) -> Tokens<'p, 'a, H, P> {
+        Tokens { parser: self }
+    }
+
+    /// Returns an iterator over the remaining lines with their tokens. Like `str::lines`,
+    /// a line break at the end of the input doesn't start another line.
+    ///
+    /// ```
+    /// use xhighlight::syntax::rust::Rust;
+    ///
+    /// let mut parser = Rust::make_parser();
+    /// parser.parse("a /* b\r\nc */");
+    /// let lines: Vec<_> = parser.lines().collect();
+    /// assert_eq!(lines[0].tokens, vec![("a", Rust::Identifier), (" ", Rust::Text), ("/*", Rust::BlockComment), (" b", Rust::BlockComment)]);
+    /// assert_eq!(lines[0].line_break, "\r\n");
+    /// assert_eq!(lines[1].state, Rust::BlockComment);
+    /// assert_eq!(lines[1].tokens, vec![("c ", Rust::BlockComment), ("*/", Rust::BlockComment)]);
+    /// ```
+    pub fn lines<'p>(&'p mut self) -> Lines<'p, 'a, H, P> {
+        let start = self.offset;
+        let number = self.position.line;
+        Lines { parser: self, pending: None, start, number }
+    }
+
+    fn transition(&mut self, transition: Transition<H>) {
+        match transition {
+            Transition::Goto(hl) => self.hl = hl,
+            Transition::Push(hl) => {
+                if self.stack.len() < self.rules.max_depth {
+                    self.stack.push(self.hl);
+                }
+                self.hl = hl;
+            },
+            Transition::Pop(0) => {},
+            Transition::Pop(n) => {
+                if n <= self.stack.len() {
+                    let len = self.stack.len() - n;
+                    self.hl = self.stack[len];
+                    self.stack.truncate(len);
+                } else {
+                    self.hl = self.rules.default_hl;
+                    self.stack.clear();
+                }
+            },
+        }
+    }
+
+    /// Emits the next token of a match that starts at the current offset. If the match
+    /// consists of several tokens, the rest is buffered, otherwise the transition is applied.
+    fn emit_buffered(&mut self, mut b: Buffered<H>) -> Token<H> {
+        let (end, highlight) = match b.groups.first() {
+            Some((range, _)) if range.start > self.offset => (range.start, b.highlight),
+            Some((range, hl)) => (range.end, *hl),
+            None => (b.end, b.highlight),
+        };
+        let token = self.emit(end, highlight, b.state);
+        if end < b.end {
+            self.add_to_trace(&token, Some((b.pattern, None)));
+            b.start = end;
+            b.groups.retain(|(range, _)| range.start >= end);
+            self.buffered = Some(b);
+        } else {
+            self.add_to_trace(&token, Some((b.pattern, Some(b.transition))));
+            self.transition(b.transition);
+        }
+        token
+    }
+
+    /// Creates the token from the current offset to `end` and advances the offset
+    fn emit(&mut self, end: usize, highlight: H, state: H) -> Token<H> {
+        let start = self.position;
+        self.position = start.advance(&self.input[self.offset .. end]);
+        let token = Token {
+            range: self.offset .. end,
+            highlight,
+            state,
+            start,
+            end: self.position,
+        };
+        self.offset = end;
+        self.add_to_context(&token);
+        token
+    }
+
+    fn add_to_context(&mut self, token: &Token<H>) {
+        let text = token.text(self.input);
+        if self.rules.context_size == 0
+            || text.trim().is_empty()
+            || self.rules.context_skip.contains(&token.highlight) {
+            return;
+        }
+        // Reuse the allocation of the oldest token
+        let mut buf = if self.context.len() >= self.rules.context_size {
+            self.context.pop_front().map(|(_, buf)| buf).unwrap_or_default()
+        } else {
+            String::new()
+        };
+        buf.clear();
+        buf.push_str(text);
+        self.context.push_back((token.highlight, buf));
+    }
+
+    fn extend_reach(&mut self, end: usize) {
+        extend_reach(&mut self.reach.as_mut(), end);
+    }
+
+    /// Records a token if tracing is enabled. `pattern` contains the index of the pattern
+    /// that matched the token, and the transition if it was applied after the token.
+    fn add_to_trace(&mut self, token: &Token<H>, pattern: Option<(usize, Option<Transition<H>>)>) {
+        let rules = &self.rules;
+        let input = self.input;
+        if let Some(trace) = &mut self.trace {
+            trace.push(TraceEntry {
+                range: token.range.clone(),
+                text: token.text(input).to_string(),
+                highlight: token.highlight,
+                state: token.state,
+                pattern: pattern.map(|(index, _)| index),
+                label: pattern.and_then(|(index, _)| {
+                    rules.matchers.get(&token.state).map(|m| format!("{:?}", m.get(index).0))
+                }),
+                transition: pattern.and_then(|(_, transition)| transition),
+            });
+        }
+    }
+
+    fn missing_matcher(&mut self) -> Result<Option<Token<H>>, ParseError<H>> {
+        // The rest of the input is handled in the same way, so it depends on where the input ends
+        self.extend_reach(self.input.len() + 1);
+        if self.offset >= self.input.len() {
+            return Ok(None);
+        }
+        match self.rules.fallback {
+            Fallback::Remainder => Ok(self.remainder(self.hl)),
+            // If the default state has no pattern set either, there's nothing else we can do
+            Fallback::DefaultState if self.hl == self.rules.default_hl => Ok(self.remainder(self.hl)),
+            Fallback::DefaultState => {
+                self.hl = self.rules.default_hl;
+                self.try_next_token()
+            },
+            Fallback::Fail => Err(ParseError::MissingMatcher { state: self.hl, offset: self.offset }),
+        }
+    }
+
+    /// Returns the rest of the input as a single token with the highlight `hl`
+    fn remainder(&mut self, hl: H) -> Option<Token<H>> {
+        self.extend_reach(self.input.len() + 1);
+        if self.offset < self.input.len() {
+            let token = self.emit(self.input.len(), hl, self.hl);
+            self.add_to_trace(&token, None);
+            Some(token)
+        } else {
+            None
+        }
+    }
 }
 
 impl<'a, H: Highlight, P: Pattern<H>> Iterator for &mut Parser<'a, H, P> {
@@ -281,53 +2364,171 @@ impl<'a, H: Highlight, P: Pattern<H>> Iterator for &mut Parser<'a, H, P> {
     }
 }
 
+/// An iterator over tokens with their positions, returned by `Parser::tokens`.
+pub struct Tokens<'p, 'a, H: Highlight, P: Pattern<H>> {
+    parser: &'p mut Parser<'a, H, P>,
+}
 
-/// This implementation is based on the UTF-8 binary representation.
-/// Note that the most common case of ASCII characters is fastest!
-///
-/// This doesn't work if the byte is a continuation byte of a multi-byte code point
-#[inline]
-fn char_len(ch: u8) -> usize {
-    if ch < 128 {
-        1
-    } else if (ch >> 5) == 0b110 {
-        2
-    } else if (ch >> 4) == 0b1110 {
-        3
-    } else {
-        4
+impl<'p, 'a, H: Highlight, P: Pattern<H>> Iterator for Tokens<'p, 'a, H, P> {
+    type Item = Token<H>;
+    fn next(&mut self) -> Option<Token<H>> {
+        self.parser.next_token()
     }
 }
 
-#[inline]
-pub unsafe fn next_char(string: &[u8], index: usize) -> char {
-    let ch = string[index];
-    if ch < 128 {
-        ch as char
-    } else {
-        let (len, mut cp) = if (ch >> 5) == 0b110 {
-            (1, (ch & 0b11111) as u32)
-        } else if (ch >> 4) == 0b1110 {
-            (2, (ch & 0b1111) as u32)
-        } else {
-            (3, (ch & 0b111) as u32)
-        };
-        cp = (cp << 6) + (string.get_unchecked(index + 1) & 0b111111) as u32;
-        if len != 1 {
-            cp = (cp << 6) + (string.get_unchecked(index + 2) & 0b111111) as u32;
-            if len != 2 {
-                cp = (cp << 6) + (string.get_unchecked(index + 3) & 0b111111) as u32;
+/// A token of input that might not be valid UTF-8, see `Parser::parse_bytes`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ByteToken<'a, H: Highlight> {
+    /// A token of valid UTF-8
+    Text(&'a str, H),
+    /// A sequence of bytes that isn't valid UTF-8
+    Invalid(&'a [u8]),
+}
+
+/// An iterator over the tokens of input that might not be valid UTF-8,
+/// returned by `Parser::parse_bytes`.
+pub struct ByteTokens<'p, 'a, H: Highlight, P: Pattern<H>> {
+    parser: &'p mut Parser<'a, H, P>,
+    chunks: std::str::Utf8Chunks<'a>,
+    /// The invalid sequence after the valid text that is currently parsed
+    invalid: Option<&'a [u8]>,
+    /// The offset of the current chunk in the input
+    start: usize,
+    /// The number of trace entries whose ranges were already made absolute
+    traced: usize,
+}
+
+impl<'p, 'a, H: Highlight, P: Pattern<H>> Iterator for ByteTokens<'p, 'a, H, P> {
+    type Item = ByteToken<'a, H>;
+    fn next(&mut self) -> Option<ByteToken<'a, H>> {
+        loop {
+            if let Some((text, hl)) = self.parser.next_match() {
+                // The parser only knows the offsets in the current chunk
+                if let Some(trace) = &mut self.parser.trace {
+                    for entry in &mut trace[self.traced ..] {
+                        entry.range.start += self.start;
+                        entry.range.end += self.start;
+                    }
+                    self.traced = trace.len();
+                }
+                return Some(ByteToken::Text(text, hl));
+            }
+            if let Some(bytes) = self.invalid.take() {
+                self.start += bytes.len();
+                return Some(ByteToken::Invalid(bytes));
+            }
+            // The valid text was parsed completely, so no match is buffered,
+            // and the parser can continue with the next chunk in the same state
+            self.start += self.parser.input.len();
+            let chunk = self.chunks.next()?;
+            let parser = &mut *self.parser;
+            parser.input = chunk.valid();
+            parser.offset = 0;
+            parser.position = Position::default();
+            self.invalid = Some(chunk.invalid()).filter(|bytes| !bytes.is_empty());
+        }
+    }
+}
+
+/// An iterator over lines and their tokens, returned by `Parser::lines`.
+pub struct Lines<'p, 'a, H: Highlight, P: Pattern<H>> {
+    parser: &'p mut Parser<'a, H, P>,
+    /// The part of a token that continues in the next line
+    pending: Option<Token<H>>,
+    /// The start offset and the number of the next line
+    start: usize,
+    number: usize,
+}
+
+impl<'p, 'a, H: Highlight, P: Pattern<H>> Iterator for Lines<'p, 'a, H, P> {
+    type Item = Line<'a, H>;
+    fn next(&mut self) -> Option<Line<'a, H>> {
+        let input = self.parser.input;
+        let start = self.start;
+        let mut state = None;
+        let mut ranges = Vec::new();
+        let mut end = None;
+
+        while let Some(mut token) = self.pending.take().or_else(|| self.parser.next_token()) {
+            state.get_or_insert(token.state);
+            match token.text(input).find('\n') {
+                Some(i) => {
+                    let line_end = token.range.start + i;
+                    if line_end + 1 < token.range.end {
+                        let mut rest = token.clone();
+                        rest.range.start = line_end + 1;
+                        self.pending = Some(rest);
+                    }
+                    token.range.end = line_end;
+                    ranges.push((token.range, token.highlight));
+                    end = Some(line_end);
+                    break;
+                },
+                None => ranges.push((token.range, token.highlight)),
             }
         }
-        use std::mem;
-        mem::transmute(cp)
+
+        let (content_end, next_start) = match end {
+            // The `\r` of a `\r\n` can be in a different token than the `\n`
+            Some(end) if end > start && input.as_bytes()[end - 1] == b'\r' => (end - 1, end + 1),
+            Some(end) => (end, end + 1),
+            None if ranges.is_empty() => return None,
+            None => (input.len(), input.len()),
+        };
+        let tokens = ranges.into_iter()
+            .map(|(range, hl)| (range.start .. range.end.min(content_end), hl))
+            .filter(|(range, _)| range.start < range.end)
+            .map(|(range, hl)| (&input[range], hl))
+            .collect();
+
+        let line = Line {
+            number: self.number,
+            range: start .. content_end,
+            state: state.unwrap_or(self.parser.hl),
+            tokens,
+            line_break: &input[content_end .. next_start],
+        };
+        self.start = next_start;
+        self.number += 1;
+        Some(line)
     }
 }
 
-/// Checks whether a string has a word boundary at a specified index.
-/// This is unsafe because it doesn't test whether the index is within the string bounds
+
+/// Returns the length of the UTF-8 sequence that starts with this byte,
+/// or `None` if no valid sequence starts with it (e.g. a continuation byte).
+/// Note that the most common case of ASCII characters is fastest!
 #[inline]
-pub unsafe fn is_word_boundary(_string: &str, _index: usize) -> bool {
-    //TODO
-    unimplemented!()
+fn char_len(first: u8) -> Option<usize> {
+    match first {
+        0x00 ..= 0x7F => Some(1),
+        0xC2 ..= 0xDF => Some(2),
+        0xE0 ..= 0xEF => Some(3),
+        0xF0 ..= 0xF4 => Some(4),
+        _ => None,
+    }
+}
+
+/// Decodes the char that starts at `index`. Returns `None` if the index is out of bounds,
+/// or if there is no valid UTF-8 sequence at the index.
+#[inline]
+pub fn next_char(string: &[u8], index: usize) -> Option<char> {
+    let len = char_len(*string.get(index)?)?;
+    let bytes = string.get(index .. index + len)?;
+    std::str::from_utf8(bytes).ok()?.chars().next()
+}
+
+/// Checks whether a string has a word boundary at a specified index, i.e. if exactly one of
+/// the characters before and after the index is a word character (`\w` in a regex).
+/// The start and the end of the string count as non-word characters.
+///
+/// Returns `false` if the index is out of bounds or not at a char boundary.
+pub fn is_word_boundary(string: &str, index: usize) -> bool {
+    if !string.is_char_boundary(index) {
+        return false;
+    }
+    let is_word = |c: Option<char>| c.is_some_and(regex_syntax::is_word_character);
+    let before = is_word(string[.. index].chars().next_back());
+    let after = is_word(string[index ..].chars().next());
+    before != after
 }
\ No newline at end of file
@@ -1,49 +1,202 @@
 use std::collections::HashMap;
 
-use crate::parse::{Parser, Highlight, Pattern};
+use crate::parse::{ByteToken, Grammar, Parser, Highlight, Limit, Pattern, StandardKind};
 
 pub trait Renderer<'a> {
     fn render(&mut self, s: &'a str) -> String;
 }
 
+/// The CSS classes of highlights, used by the HTML renderers.
+///
+/// A class is looked up for the highlight itself, then for its parents (see
+/// `Highlight::parent`), and then for the standard kinds of these highlights
+/// (see `Highlight::standard_kind`).
+#[derive(Clone, Debug)]
+pub struct ClassMap<'c, H: Highlight> {
+    highlights: HashMap<H, &'c str>,
+    kinds: HashMap<StandardKind, &'c str>,
+}
+
+impl<'c, H: Highlight> Default for ClassMap<'c, H> {
+    fn default() -> Self {
+        ClassMap { highlights: HashMap::new(), kinds: HashMap::new() }
+    }
+}
+
+impl<'c, H: Highlight> ClassMap<'c, H> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_mapping(&mut self, class_map: &[(H, &'c str)]) -> &mut Self {
+        self.highlights = class_map.iter().cloned().collect();
+        self
+    }
+
+    pub fn set_standard_mapping(&mut self, class_map: &[(StandardKind, &'c str)]) -> &mut Self {
+        self.kinds = class_map.iter().cloned().collect();
+        self
+    }
+
+    /// Returns the class of a highlight, or `""` if it has none
+    pub fn resolve(&self, hl: H) -> &'c str {
+        let ancestors = || std::iter::successors(Some(hl), |h| h.parent());
+        ancestors()
+            .find_map(|h| self.highlights.get(&h))
+            .or_else(|| ancestors().find_map(|h| self.kinds.get(&h.standard_kind()?)))
+            .copied()
+            .unwrap_or("")
+    }
+}
+
 pub struct HtmlRenderer<'a, H: Highlight, P: Pattern<H>> {
     parser: &'a mut Parser<'a, H, P>,
-    class_map: HashMap<H, &'a str>,
+    classes: ClassMap<'a, H>,
 }
 
 impl<'a, H: Highlight, P: Pattern<H>> HtmlRenderer<'a, H, P> {
     pub fn new(parser: &'a mut Parser<'a, H, P>) -> Self {
         Self {
             parser,
-            class_map: HashMap::with_capacity(0),
+            classes: ClassMap::new(),
         }
     }
+    /// Sets the CSS classes of the highlights. A highlight without a class gets the class
+    /// of its parent (see `Highlight::parent`). Text without a class isn't wrapped in a `<span>`.
     pub fn set_mapping(mut self, class_map: &[(H, &'a str)]) -> Self {
-        self.class_map = HashMap::with_capacity(class_map.len());
-        for &(hl, s) in class_map {
-            self.class_map.insert(hl, s);
-        }
+        self.classes.set_mapping(class_map);
+        self
+    }
+
+    /// Sets the CSS classes of standard kinds (see `Highlight::standard_kind`), so the same
+    /// classes can be used for every language. They are used for highlights that don't
+    /// have a class from `set_mapping`.
+    pub fn set_standard_mapping(mut self, class_map: &[(StandardKind, &'a str)]) -> Self {
+        self.classes.set_standard_mapping(class_map);
         self
     }
 }
 
+impl<'a, H: Highlight, P: Pattern<H>> HtmlRenderer<'a, H, P> {
+    /// Renders tokens that were already parsed, e.g. a single line
+    pub fn render_tokens<'t, I>(&self, tokens: I) -> String
+        where I: IntoIterator<Item = (&'t str, H)>
+    {
+        render_html(&self.classes, tokens)
+    }
+
+    /// Renders input that might not be valid UTF-8, see `Parser::parse_bytes`.
+    /// Invalid sequences are replaced with `U+FFFD`, like `String::from_utf8_lossy` does.
+    pub fn render_bytes(&mut self, s: &'a [u8]) -> String {
+        render_html_lossy(&self.classes, self.parser.parse_bytes(s))
+    }
+
+    /// Returns the limit that was exceeded during the last `render` call, if any.
+    /// The limits are set with `Parser::set_limits`.
+    pub fn limit_exceeded(&self) -> Option<Limit> {
+        self.parser.limit_exceeded()
+    }
+}
+
 impl<'a, H: Highlight, P: Pattern<H>> Renderer<'a> for HtmlRenderer<'a, H, P> {
     fn render(&mut self, s: &'a str) -> String {
         self.parser.parse(s);
+        render_html(&self.classes, &mut *self.parser)
+    }
+}
 
-        let mut s = String::new();
-        for (token, hl) in &mut self.parser {
-            let &cls = self.class_map.get(&hl).unwrap_or(&"");
-            if cls.is_empty() {
-                s.push_str(token);
-            } else {
-                s.push_str("<span class=\"");
-                s.push_str(cls);
-                s.push_str("\">");
-                s.push_str(token.replace("<", "&lt;").as_str());
-                s.push_str("</span>");
-            }
+/// Like `HtmlRenderer`, but owns a grammar and creates a parser for every input.
+/// Since `render` only needs `&self`, one renderer can be shared between threads,
+/// e.g. in the managed state of a web server.
+pub struct SharedHtmlRenderer<'c, H: Highlight + 'static, P: Pattern<H> + 'static> {
+    grammar: Grammar<H, P>,
+    classes: ClassMap<'c, H>,
+}
+
+impl<'c, H: Highlight + 'static, P: Pattern<H> + 'static> SharedHtmlRenderer<'c, H, P> {
+    pub fn new(grammar: Grammar<H, P>) -> Self {
+        Self {
+            grammar,
+            classes: ClassMap::new(),
+        }
+    }
+    /// See `HtmlRenderer::set_mapping`
+    pub fn set_mapping(mut self, class_map: &[(H, &'c str)]) -> Self {
+        self.classes.set_mapping(class_map);
+        self
+    }
+
+    /// See `HtmlRenderer::set_standard_mapping`
+    pub fn set_standard_mapping(mut self, class_map: &[(StandardKind, &'c str)]) -> Self {
+        self.classes.set_standard_mapping(class_map);
+        self
+    }
+
+    pub fn render(&self, s: &str) -> String {
+        self.render_checked(s).0
+    }
+
+    /// Like `render`, but also returns the limit that was exceeded, if any.
+    /// The limits are set with `Grammar::set_limits`.
+    pub fn render_checked(&self, s: &str) -> (String, Option<Limit>) {
+        let mut parser = self.grammar.parser();
+        parser.parse(s);
+        let html = render_html(&self.classes, &mut parser);
+        (html, parser.limit_exceeded())
+    }
+
+    /// See `HtmlRenderer::render_bytes`
+    pub fn render_bytes(&self, s: &[u8]) -> String {
+        let mut parser = self.grammar.parser();
+        render_html_lossy(&self.classes, parser.parse_bytes(s))
+    }
+
+    /// Renders tokens that were already parsed, e.g. a single line
+    pub fn render_tokens<'t, I>(&self, tokens: I) -> String
+        where I: IntoIterator<Item = (&'t str, H)>
+    {
+        render_html(&self.classes, tokens)
+    }
+}
+
+impl<'a, 'c, H: Highlight + 'static, P: Pattern<H> + 'static> Renderer<'a> for SharedHtmlRenderer<'c, H, P> {
+    fn render(&mut self, s: &'a str) -> String {
+        SharedHtmlRenderer::render(self, s)
+    }
+}
+
+fn render_html<'t, H: Highlight, I>(classes: &ClassMap<H>, tokens: I) -> String
+    where I: IntoIterator<Item = (&'t str, H)>
+{
+    let mut s = String::new();
+    for (token, hl) in tokens {
+        push_html(&mut s, classes, token, hl);
+    }
+    s
+}
+
+fn render_html_lossy<'t, H: Highlight, I>(classes: &ClassMap<H>, tokens: I) -> String
+    where I: IntoIterator<Item = ByteToken<'t, H>>
+{
+    let mut s = String::new();
+    for token in tokens {
+        match token {
+            ByteToken::Text(token, hl) => push_html(&mut s, classes, token, hl),
+            ByteToken::Invalid(_) => s.push('\u{FFFD}'),
         }
-        s
     }
-}
\ No newline at end of file
+    s
+}
+
+fn push_html<H: Highlight>(s: &mut String, classes: &ClassMap<H>, token: &str, hl: H) {
+    let cls = classes.resolve(hl);
+    if cls.is_empty() {
+        s.push_str(token);
+    } else {
+        s.push_str("<span class=\"");
+        s.push_str(cls);
+        s.push_str("\">");
+        s.push_str(token.replace("<", "&lt;").as_str());
+        s.push_str("</span>");
+    }
+}
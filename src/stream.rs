@@ -0,0 +1,192 @@
+use std::{
+    char::REPLACEMENT_CHARACTER,
+    collections::VecDeque,
+    io::{self, BufRead, ErrorKind},
+    str,
+};
+
+use crate::parse::{Budget, Highlight, Limit, Parser, ParserState, Pattern};
+
+
+/// Highlights text from a reader without reading all of it into memory, e.g. a large log file.
+/// The tokens are owned, since the text they were parsed from is discarded.
+///
+/// The input is read in chunks. A token is only emitted when at least `lookahead` bytes
+/// follow it (or the input ends), so a pattern can see that many bytes after the end of its
+/// match. Tokens that don't fit into the buffered text are parsed again after reading more;
+/// the buffer grows geometrically while that happens, so the memory is bounded by about
+/// twice the longest token plus the lookahead. Invalid UTF-8 is replaced with `U+FFFD`,
+/// like `String::from_utf8_lossy` does.
+///
+/// The tokens are the same as with `Parser::parse`, unless a pattern needs more than the
+/// lookahead to decide whether it matches, e.g. a string literal that is longer than the
+/// lookahead and doesn't have its closing quote yet.
+///
+/// The limits of the parser (see `Parser::set_limits`) apply to the whole stream: `max_input`
+/// to the number of bytes read, and the steps and the time are shared by all chunks. The time
+/// starts with the first chunk. Text that is parsed again after reading more counts again.
+/// When a limit is exceeded, the rest of the stream is emitted in tokens of the default state,
+/// one per chunk, and `limit_exceeded` returns the limit.
+///
+/// ```
+/// use xhighlight::stream::TokenStream;
+/// use xhighlight::syntax::rust::Rust;
+///
+/// let input: &[u8] = b"let a = 1;\nlet b = 2;\n";
+/// let tokens = TokenStream::new(Rust::make_parser(), input)
+///     .collect::<std::io::Result<Vec<_>>>()
+///     .unwrap();
+/// assert_eq!(tokens[0], ("let".to_string(), Rust::Keyword));
+/// ```
+pub struct TokenStream<'a, H: Highlight, P: Pattern<H>, R: BufRead> {
+    parser: Parser<'a, H, P>,
+    reader: R,
+    /// The text that wasn't emitted yet
+    buf: String,
+    /// The bytes of an incomplete UTF-8 sequence at the end of the text that was read
+    partial: Vec<u8>,
+    /// The lexical state at the start of `buf`
+    state: ParserState<H>,
+    tokens: VecDeque<(String, H)>,
+    lookahead: usize,
+    eof: bool,
+    /// The number of bytes that were read
+    read: usize,
+    /// The budget that the parser left after the last chunk
+    budget: Option<Budget>,
+}
+
+impl<'a, H: Highlight, P: Pattern<H>, R: BufRead> TokenStream<'a, H, P, R> {
+    /// Creates a token stream. The parser is only used as a template and doesn't need any input.
+    pub fn new(parser: Parser<'a, H, P>, reader: R) -> Self {
+        TokenStream {
+            state: parser.snapshot(),
+            parser,
+            reader,
+            buf: String::new(),
+            partial: Vec::new(),
+            tokens: VecDeque::new(),
+            lookahead: 8192,
+            eof: false,
+            read: 0,
+            budget: None,
+        }
+    }
+
+    /// Sets the number of bytes that must follow a token before it is emitted.
+    /// The default is 8192.
+    pub fn set_lookahead(mut self, lookahead: usize) -> Self {
+        self.lookahead = lookahead.max(1);
+        self
+    }
+
+    /// Returns the limit that was exceeded so far, if any
+    pub fn limit_exceeded(&self) -> Option<Limit> {
+        self.budget.as_ref().and_then(Budget::exceeded)
+    }
+
+    /// Reads until at least `lookahead` more bytes are buffered, or the input ends.
+    /// If the buffer is longer than the lookahead, because a token didn't fit into it,
+    /// its size is doubled, so long tokens aren't parsed again too often.
+    fn fill(&mut self) -> io::Result<()> {
+        let target = self.buf.len() + self.lookahead.max(self.buf.len());
+        while !self.eof && self.buf.len() < target {
+            let available = match self.reader.fill_buf() {
+                Ok(bytes) => bytes,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if available.is_empty() {
+                // A sequence that is still incomplete at the end of the input is invalid
+                self.buf.push_str(&String::from_utf8_lossy(&self.partial));
+                self.partial.clear();
+                self.eof = true;
+                break;
+            }
+            let len = available.len().min(target - self.buf.len());
+            self.partial.extend_from_slice(&available[.. len]);
+            self.reader.consume(len);
+            self.read += len;
+            self.decode();
+        }
+        Ok(())
+    }
+
+    /// Moves the bytes that were read to `buf`, except for an incomplete sequence at the end
+    fn decode(&mut self) {
+        let mut bytes = &self.partial[..];
+        loop {
+            match str::from_utf8(bytes) {
+                Ok(text) => {
+                    self.buf.push_str(text);
+                    bytes = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, rest) = bytes.split_at(e.valid_up_to());
+                    self.buf.push_str(str::from_utf8(valid).unwrap());
+                    match e.error_len() {
+                        Some(len) => {
+                            self.buf.push(REPLACEMENT_CHARACTER);
+                            bytes = &rest[len ..];
+                        }
+                        None => {
+                            bytes = rest;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        let decoded = self.partial.len() - bytes.len();
+        self.partial.drain(.. decoded);
+    }
+
+    /// Parses the buffered text and moves the tokens that can be emitted to `tokens`
+    fn lex(&mut self) {
+        let mut parser = self.parser.fork();
+        parser.parse(&self.buf);
+        parser.restore(&self.state);
+        // The budget of the first chunk is the one `parse` created
+        let budget = self.budget.take().unwrap_or_else(|| parser.budget().clone());
+        parser.continue_budget(&budget, self.read);
+
+        let limit = if self.eof { self.buf.len() } else { self.buf.len().saturating_sub(self.lookahead) };
+        let mut consumed = 0;
+        while let Some(token) = parser.next_token() {
+            // After a limit is exceeded, the rest is emitted without waiting for more text
+            if token.range.end > limit && parser.limit_exceeded().is_none() {
+                break;
+            }
+            consumed = token.range.end;
+            self.tokens.push_back((token.text(&self.buf).to_string(), token.highlight));
+            self.state = parser.snapshot();
+        }
+        self.budget = Some(parser.budget().clone());
+        if self.eof {
+            // The rest is discarded if the parser stopped early, e.g. because of `Fallback::Fail`
+            self.buf.clear();
+        } else {
+            self.buf.drain(.. consumed);
+        }
+    }
+}
+
+impl<'a, H: Highlight, P: Pattern<H>, R: BufRead> Iterator for TokenStream<'a, H, P, R> {
+    type Item = io::Result<(String, H)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(token) = self.tokens.pop_front() {
+                return Some(Ok(token));
+            }
+            if self.eof && self.buf.is_empty() {
+                return None;
+            }
+            if let Err(e) = self.fill() {
+                return Some(Err(e));
+            }
+            self.lex();
+        }
+    }
+}
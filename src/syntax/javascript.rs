@@ -1,33 +1,59 @@
 
 use crate::{
-    parse::{Highlight, Parser, RegexPat},
+    parse::{AnyPat, Context, Highlight, KeywordPat, RegexPat, Transition::{self, Goto, Push}},
     syntax::javascript::JS::*,
 };
 
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Highlight)]
 pub enum JS {
+    #[highlight(kind = "text")]
     Text,
+    #[highlight(class = "kwd", kind = "keyword")]
     Keyword,
+    #[highlight(kind = "identifier")]
     Identifier,
+    #[highlight(class = "opr", kind = "operator")]
     Operator,
+    #[highlight(class = "typ", kind = "type")]
     CommonType,
+    #[highlight(class = "pun", kind = "punctuation")]
     Punctuation,
+    /// The parent of all kinds of comments; not emitted by the parser
+    #[highlight(class = "com", kind = "comment")]
+    Comment,
+    #[highlight(kind = "comment", parent = "Comment")]
     LineComment,
+    #[highlight(kind = "comment", parent = "Comment")]
     BlockComment,
+    #[highlight(class = "str", kind = "string")]
     String,
+    #[highlight(class = "esc", kind = "escape", parent = "String")]
     StringEscape,
+    #[highlight(class = "num", kind = "number")]
     Number,
+    #[highlight(class = "boo", kind = "bool")]
     Bool,
+    #[highlight(class = "fun", kind = "function")]
     FnCall,
+    #[highlight(class = "reg", kind = "regex")]
     Regex,
+    #[highlight(class = "tpl", kind = "string", parent = "String")]
     TemplateString,
+    #[highlight(class = "tpi", kind = "text", parent = "TemplateString")]
     TplInner
 }
 
-impl Highlight for JS {}
 
-const KEYWORD: &str = r"(abstract|arguments|await|boolean|break|byte|case|catch|char|class|const|continue|debugger|default|delete|do|double|else|enum|eval|export|extends|final|finally|float|for|function|goto|if|implements|import|in|instanceof|int|interface|let|long|native|new|null|package|private|protected|public|return|short|static|super|switch|synchronized|this|throw|throws|transient|try|typeof|var|void|volatile|while|with|yield)\b";
+const KEYWORDS: &[&str] = &[
+    "abstract", "arguments", "await", "boolean", "break", "byte", "case", "catch", "char", "class",
+    "const", "continue", "debugger", "default", "delete", "do", "double", "else", "enum", "eval",
+    "export", "extends", "final", "finally", "float", "for", "function", "goto", "if", "implements",
+    "import", "in", "instanceof", "int", "interface", "let", "long", "native", "new", "null",
+    "package", "private", "protected", "public", "return", "short", "static", "super", "switch",
+    "synchronized", "this", "throw", "throws", "transient", "try", "typeof", "var", "void",
+    "volatile", "while", "with", "yield",
+];
 
 const OPERATOR: &str = r"[=&+\-*/%~!|^?:<>]\s*";
 const PUNCTUATION: &str = r"[.,;()\[\]{}]\s*";
@@ -39,68 +65,75 @@ const BLOCK_COMMENT: &str = r"/\*.*?\*/";
 
 const NUMBER: &str = r"0b[01]+|0x[0-9a-fA-F]+|0o[0-7]+|(\d*\.\d+|\d+)([eE][+-]?\d+)?";
 
-const BOOL: &str = r"\b(true|false)\b";
+const BOOL: &str = r"(true|false)\b";
 
-const FUNCTION: &str = r"[\p{Alphabetic}_]\w*\b";
+const FUNCTION: &str = r"[\p{Alphabetic}_$][\w$]*";
 const FUNCTION_AHEAD: &str = r"\s*\(";
 
 const STRING: &str = r###""(\\.|[^"])*"|'(\\.|[^'])*'"###;
 
-const TPL_STR_R: &str = r###"`[^`]*?\$\{"###;
-const TPL_STR: &str = r###"`.*?`"###;
-const TPL_STR_LR: &str = r###"}[^`]*?\$\{"###;
-const TPL_STR_L: &str = r###"}.*?`"###;
-
-const REG_KWD: &str = r"(else|in|instanceof|typeof)\b\s*";
-const REG_OPER: &str = r"[=&+\-*/%~!|^?:<>]\s*";
-const REG_PUNCT: &str = r"[,;()\[\]{}]\s*";
+const TPL_STR: &str = r"`";
+const TPL_STR_ESC: &str = r"\\.";
+const TPL_INNER: &str = r"\$\{";
+const OPEN_BRACE: &str = r"\{\s*";
+const CLOSE_BRACE: &str = r"}\s*";
+const CLOSE_TPL_INNER: &str = r"}";
 
 const REGEX: &str = r###"/(\\.|[^*/])(\\.|[^/])*/i?g?m?y?"###;
 
-lazy_static! {
-    static ref TEXT_REG: Vec<(RegexPat<JS>, JS)> = {
-        vec![
-            (RegexPat::regex     (LINE_COMMENT,             LineComment),        Text),
-            (RegexPat::shortest  (BLOCK_COMMENT,            BlockComment),       Text),
-
-            (RegexPat::look_ahead(REG_KWD, REGEX,           Keyword),            Regex),
-            (RegexPat::look_ahead(REG_OPER, REGEX,          Operator),           Regex),
-            (RegexPat::look_ahead(REG_PUNCT, REGEX,         Punctuation),        Regex),
-
-            (RegexPat::shortest  (KEYWORD,                  Keyword),            Text),
-            (RegexPat::shortest  (OPERATOR,                 Operator),           Text),
-            (RegexPat::regex     (PUNCTUATION,              Punctuation),        Text),
-            (RegexPat::shortest  (TYPE,                     CommonType),         Text),
-            (RegexPat::shortest  (BOOL,                     Bool),               Text),
-            (RegexPat::optional  (FUNCTION, FUNCTION_AHEAD, FnCall, Identifier), Text),
-            (RegexPat::regex     (NUMBER,                   Number),             Text),
-            (RegexPat::regex     (STRING,                   String),             Text),
-
-            (RegexPat::regex     (TPL_STR_R,                TemplateString),     TplInner),
-            (RegexPat::regex     (TPL_STR,                  TemplateString),     Text),
-        ]
-    };
+/// Identifiers can contain `$`
+fn is_word_char(c: char) -> bool {
+    c == '$' || regex_syntax::is_word_character(c)
 }
-lazy_static! {
-    static ref REGEX_REG: Vec<(RegexPat<JS>, JS)> = {
-        vec![ (RegexPat::regex   (REGEX,                    Regex),              Text) ]
-    };
+
+/// A `/` starts a regex literal, unless it follows a value, which is divided
+fn regex_allowed(context: &Context<JS>) -> bool {
+    match context.last() {
+        None => true,
+        Some((_, Operator)) => true,
+        Some((text, Keyword)) => !matches!(text, "this" | "super" | "null" | "arguments"),
+        Some((text, Punctuation)) => !text.trim_end().ends_with(&[')', ']', '}'][..]),
+        Some((text, TemplateString)) => text == "${",
+        Some(_) => false,
+    }
 }
-lazy_static! {
-    static ref TPL_REG: Vec<(RegexPat<JS>, JS)> = {
-        vec![
-            (RegexPat::regex     (TPL_STR_LR,               TemplateString),     TplInner),
-            (RegexPat::regex     (TPL_STR_L,                TemplateString),     Text),
-        ]
-    };
+
+/// The patterns for JavaScript code. Template strings contain code as well,
+/// so this is used for the `Text` and the `TplInner` state.
+fn code_reg(state: JS) -> Vec<(AnyPat<JS>, Transition<JS>)> {
+    vec![
+        (RegexPat::regex     (LINE_COMMENT,             LineComment).into(),        Goto(state)),
+        (RegexPat::shortest  (BLOCK_COMMENT,            BlockComment).into(),       Goto(state)),
+
+        (RegexPat::after     (REGEX, regex_allowed,     Regex).into(),              Goto(state)),
+
+        (KeywordPat::new(KEYWORDS, Keyword).set_word_chars(is_word_char).into(),    Goto(state)),
+        (RegexPat::shortest  (OPERATOR,                 Operator).into(),           Goto(state)),
+        (RegexPat::regex     (OPEN_BRACE,               Punctuation).into(),        Push(Text)),
+        (RegexPat::regex     (PUNCTUATION,              Punctuation).into(),        Goto(state)),
+        (RegexPat::at_boundary(TYPE,                    CommonType).into(),         Goto(state)),
+        (RegexPat::at_boundary(BOOL,                    Bool).into(),               Goto(state)),
+        (RegexPat::optional  (FUNCTION, FUNCTION_AHEAD, FnCall, Identifier).into(), Goto(state)),
+        (RegexPat::regex     (NUMBER,                   Number).into(),             Goto(state)),
+        (RegexPat::regex     (STRING,                   String).into(),             Goto(state)),
+
+        (RegexPat::regex     (TPL_STR,                  TemplateString).into(),     Push(TemplateString)),
+    ]
 }
 
-impl JS {
-    pub fn make_parser<'a>() -> Parser<'a, JS, RegexPat<JS>> {
-        let mut parser = Parser::new(Text);
-        parser.add_matcher(Text, &TEXT_REG);
-        parser.add_matcher(Regex, &REGEX_REG);
-        parser.add_matcher(TplInner, &TPL_REG);
-        parser
-    }
+grammar! {
+    JS: AnyPat<JS>, default Text, |grammar| grammar.set_context_window(1, &[LineComment, BlockComment]);
+
+    // Braces are pushed, so we know when a `${` block in a template string ends
+    Text => [
+        RegexPat::regex     (CLOSE_BRACE,              Punctuation)    => Pop(1),
+    ] + code_reg(Text),
+    TemplateString => [
+        RegexPat::regex     (TPL_STR_ESC,              StringEscape)   => TemplateString,
+        RegexPat::regex     (TPL_INNER,                TemplateString) => Push(TplInner),
+        RegexPat::regex     (TPL_STR,                  TemplateString) => Pop(1),
+    ],
+    TplInner => [
+        RegexPat::regex     (CLOSE_TPL_INNER,          TemplateString) => Pop(1),
+    ] + code_reg(TplInner),
 }
@@ -1,37 +1,61 @@
 
 use crate::{
-    parse::{Highlight, Parser, RegexPat},
+    parse::{is_word_boundary, AnyPat, FnPat, KeywordPat, Highlight, RegexPat},
     syntax::rust::Rust::*,
 };
 
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Highlight)]
 pub enum Rust {
+    #[highlight(kind = "text")]
     Text,
+    #[highlight(class = "kwd", kind = "keyword")]
     Keyword,
+    #[highlight(kind = "identifier")]
     Identifier,
+    #[highlight(class = "lif", kind = "lifetime")]
     Lifetime,
+    #[highlight(class = "opr", kind = "operator")]
     Operator,
+    #[highlight(class = "pun", kind = "punctuation")]
     Punctuation,
+    #[highlight(class = "ann", kind = "annotation")]
     Annotation,
+    /// The parent of all kinds of comments; not emitted by the parser
+    #[highlight(class = "com", kind = "comment")]
+    Comment,
+    #[highlight(kind = "comment", parent = "Comment")]
     LineComment,
+    #[highlight(kind = "comment", parent = "Comment")]
     BlockComment,
+    #[highlight(class = "doc", kind = "doc_comment", parent = "Comment")]
     DocComment,
+    #[highlight(class = "typ", kind = "type")]
     PrimitiveType,
+    #[highlight(class = "str", kind = "string")]
     String,
+    #[highlight(class = "esc", kind = "escape", parent = "String")]
     StringEscape,
+    #[highlight(class = "str", kind = "char")]
     Char,
+    #[highlight(class = "num", kind = "number")]
     Number,
+    #[highlight(class = "boo", kind = "bool")]
     Bool,
+    #[highlight(kind = "identifier", parent = "Identifier")]
     RawLiteral,
+    #[highlight(class = "fun", kind = "function")]
     FnCall,
+    #[highlight(class = "mac", kind = "macro")]
     MacroCall,
 }
 
-impl Highlight for Rust {}
-
-
-const KEYWORD: &str = r"(as|async|box|break|const|continue|crate|dyn|else|enum|extern|fn|for|if|impl|in|let|loop|match|mod|move|mut|pub|ref|return|[sS]elf|static|struct|super|trait|type|union|unsafe|use|where|while|yield)\b";
+const KEYWORDS: &[&str] = &[
+    "as", "async", "box", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref",
+    "return", "self", "Self", "static", "struct", "super", "trait", "type", "union", "unsafe",
+    "use", "where", "while", "yield",
+];
 
 const LIFETIME: &str = r"'[\p{Alphabetic}_]\w*\b";
 
@@ -43,7 +67,8 @@ const TYPE: &str = r"(bool|char|[ui](8|16|32|64|128|size)|f32|f64|Option|Some|No
 
 const DOC_COMMENT: &str = r"//[/!].*|/\*[/*].*?\*/";
 const LINE_COMMENT: &str = r"//.*";
-const BLOCK_COMMENT: &str = r"/\*.*?\*/";
+const BLOCK_COMMENT_START: &str = r"/\*";
+const BLOCK_COMMENT_END: &str = r"\*/";
 
 const NUMBER: &str = r"(?x)
     0b ( [01] _* )+                         ( [ui] (8|1(6|28)|32|64|size) )? |
@@ -51,7 +76,7 @@ const NUMBER: &str = r"(?x)
     (\d_*)+ ( \. (\d_*)+ )? ( [eE] [+-]? _* (\d_*)+ )? ( [ui] (8|1(6|28)|32|64|size) )?
 ";
 
-const BOOL: &str = r"\b(true|false)\b";
+const BOOL: &str = r"(true|false)\b";
 
 const RAW_LITERAL: &str = r"r#[\p{Alphabetic}_]\w*\b";
 
@@ -62,58 +87,75 @@ const FUNCTION_AHEAD: &str = r"\s*\(";
 const CHAR: &str = r"'[^'\\]'|'\\.+'";
 const STRING_QUOTE: &str = r#"b?""#;
 const STRING_ESC: &str = r#"\\([nrt\\0'"\n]|x[\da-fA-F]{2}|u[\da-fA-F]{1,6})"#;
-// This should be enough:
-const RAW_STRING: &str = r#################"(?sx)
-b?r(      ".*?"          |
-         #".*?"#         |
-        ##".*?"##        |
-       ###".*?"###       |
-      ####".*?"####      |
-     #####".*?"#####     |
-    ######".*?"######    |
-   #######".*?"#######   |
-  ########".*?"########  |
- #########".*?"######### |
-##########".*?"##########)"#################;
-
-lazy_static! {
-    static ref TEXT_REG: Vec<(RegexPat<Rust>, Rust)> = {
-        vec![
-            (RegexPat::shortest  (KEYWORD,                  Keyword),            Text),
-            (RegexPat::regex     (DOC_COMMENT,              DocComment),         Text),
-            (RegexPat::regex     (LINE_COMMENT,             LineComment),        Text),
-            (RegexPat::shortest  (BLOCK_COMMENT,            BlockComment),       Text),
-            (RegexPat::shortest  (OPERATOR,                 Operator),           Text),
-            (RegexPat::regex     (PUNCTUATION,              Punctuation),        Text),
-            (RegexPat::shortest  (TYPE,                     PrimitiveType),      Text),
-            (RegexPat::shortest  (BOOL,                     Bool),               Text),
-            (RegexPat::shortest  (RAW_LITERAL,              RawLiteral),         Text),
-            (RegexPat::optional  (FUNCTION, FUNCTION_AHEAD, FnCall, Identifier), Text),
-            (RegexPat::regex     (NUMBER,                   Number),             Text),
-            (RegexPat::shortest  (MACRO,                    MacroCall),          Text),
-            (RegexPat::regex     (RAW_STRING,               String),             Text),
-            (RegexPat::shortest  (CHAR,                     Char),               Text),
-            (RegexPat::shortest  (LIFETIME,                 Lifetime),           Text),
-            (RegexPat::regex     (STRING_QUOTE,             String),             String),
-            (RegexPat::shortest  (ANNOTATION,               Annotation),         Text),
-        ]
+
+/// Raw strings can have any number of `#`, so they are scanned by hand
+fn raw_string(s: &str, index: usize) -> Option<usize> {
+    if !is_word_boundary(s, index) {
+        return None;
+    }
+    let bytes = &s.as_bytes()[index ..];
+    let prefix = match bytes {
+        [b'b', b'r', ..] => 2,
+        [b'r', ..] => 1,
+        _ => return None,
     };
+    let hashes = bytes[prefix ..].iter().take_while(|&&b| b == b'#').count();
+    if bytes.get(prefix + hashes) != Some(&b'"') {
+        return None;
+    }
+    let content = index + prefix + hashes + 1;
+    let end = format!("\"{}", "#".repeat(hashes));
+    s[content ..].find(&end).map(|i| content + i + end.len())
 }
-lazy_static! {
-    static ref STRING_REG: Vec<(RegexPat<Rust>, Rust)> = {
-        vec![
-            (RegexPat::shortest  (STRING_ESC,   StringEscape), String),
-            (RegexPat::shortest  (STRING_QUOTE, String),       Text),
-        ]
+
+/// Returns the end of the text `raw_string` looks at, see `Pattern::reach`
+fn raw_string_reach(s: &str, index: usize) -> usize {
+    let bytes = &s.as_bytes()[index ..];
+    let prefix = match bytes {
+        [b'b', b'r', ..] => 2,
+        [b'r', ..] => 1,
+        // The word boundary check looks at the char at `index`
+        _ => return (index + 4).min(s.len() + 1),
     };
+    let hashes = bytes[prefix ..].iter().take_while(|&&b| b == b'#').count();
+    if bytes.get(prefix + hashes) != Some(&b'"') {
+        return index + prefix + hashes + 1;
+    }
+    // Without the closing quote, the rest of the input is searched
+    raw_string(s, index).unwrap_or(s.len() + 1)
 }
 
-
-impl Rust {
-    pub fn make_parser<'a>() -> Parser<'a, Rust, RegexPat<Rust>> {
-        let mut parser = Parser::new(Text);
-        parser.add_matcher(Text, &TEXT_REG);
-        parser.add_matcher(String, &STRING_REG);
-        parser
-    }
+grammar! {
+    Rust: AnyPat<Rust>, default Text;
+
+    Text => [
+        KeywordPat::new(KEYWORDS,                       Keyword)            => Text,
+        RegexPat::regex     (DOC_COMMENT,               DocComment)         => Text,
+        RegexPat::regex     (LINE_COMMENT,              LineComment)        => Text,
+        RegexPat::regex     (BLOCK_COMMENT_START,       BlockComment)       => BlockComment,
+        RegexPat::shortest  (OPERATOR,                  Operator)           => Text,
+        RegexPat::regex     (PUNCTUATION,               Punctuation)        => Text,
+        RegexPat::at_boundary(TYPE,                     PrimitiveType)      => Text,
+        RegexPat::at_boundary(BOOL,                     Bool)               => Text,
+        FnPat::new(raw_string, String).set_first_bytes(b"br")
+            .set_reach(raw_string_reach)                                    => Text,
+        RegexPat::shortest  (RAW_LITERAL,               RawLiteral)         => Text,
+        RegexPat::optional  (FUNCTION, FUNCTION_AHEAD,  FnCall, Identifier) => Text,
+        RegexPat::regex     (NUMBER,                    Number)             => Text,
+        RegexPat::shortest  (MACRO,                     MacroCall)          => Text,
+        RegexPat::shortest  (CHAR,                      Char)               => Text,
+        RegexPat::shortest  (LIFETIME,                  Lifetime)           => Text,
+        RegexPat::regex     (STRING_QUOTE,              String)             => String,
+        RegexPat::shortest  (ANNOTATION,                Annotation)         => Text,
+    ],
+    String => [
+        RegexPat::shortest  (STRING_ESC,                StringEscape)       => String,
+        RegexPat::shortest  (STRING_QUOTE,              String)             => Text,
+    ],
+    // Block comments can be nested. The outermost comment is entered without pushing
+    // a state, so the last `*/` pops from the empty stack and returns to Text
+    BlockComment => [
+        RegexPat::regex     (BLOCK_COMMENT_START,       BlockComment)       => Push(BlockComment),
+        RegexPat::regex     (BLOCK_COMMENT_END,         BlockComment)       => Pop(1),
+    ],
 }
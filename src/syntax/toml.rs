@@ -1,26 +1,34 @@
 
 use crate::{
-    parse::{Highlight, Parser, RegexPat},
+    parse::{Highlight, RegexPat},
     syntax::toml::Toml::*,
 };
 
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Highlight)]
 pub enum Toml {
+    #[highlight(kind = "text")]
     Text,
+    #[highlight(kind = "text")]
     Arr0,
+    #[highlight(kind = "text")]
     Arr1,
+    #[highlight(class = "com", kind = "comment")]
     Comment,
+    #[highlight(class = "sec", kind = "section")]
     Section,
+    #[highlight(class = "opr", kind = "operator")]
     Equals,
+    #[highlight(class = "pun", kind = "punctuation")]
     Punctuation,
+    #[highlight(class = "key", kind = "key")]
     Name,
+    #[highlight(class = "str", kind = "string")]
     String,
+    #[highlight(class = "lit", kind = "constant")]
     Literal,
 }
 
-impl Highlight for Toml {}
-
 const COMMENT: &str = r"#[^\n]*";
 
 const SECTION: &str = r"(?m)\n\s*\[[^]]*]";
@@ -38,58 +46,36 @@ const CLOSE_ARR: &str = r"]";
 
 const COMMA: &str = r",\s*";
 
-lazy_static! {
-    static ref TEXT_REG: Vec<(RegexPat<Toml>, Toml)> = {
-        vec![
-            (RegexPat::regex(COMMENT,      Comment),     Text),
-            (RegexPat::regex(SECTION,      Section),     Text),
-            (RegexPat::regex(CLOSE_ARR,    Punctuation), Arr0),
-            (RegexPat::regex(COMMA,        Punctuation), Arr0),
-            (RegexPat::regex(NAME,         Name),        Equals),
-        ]
-    };
-}
-lazy_static! {
-    static ref EQUALS_REG: Vec<(RegexPat<Toml>, Toml)> = {
-        vec![ (RegexPat::regex(EQUALS,     Equals),      Arr0) ]
-    };
-}
-lazy_static! {
-    static ref ARR0_REG: Vec<(RegexPat<Toml>, Toml)> = {
-        vec![
-            (RegexPat::regex(STRING,       String),      Text),
-            (RegexPat::regex(LITERAL,      Literal),     Text),
-            (RegexPat::regex(OPEN_ARR,     Punctuation), Arr1),
-            (RegexPat::regex(CLOSE_ARR,    Punctuation), Arr0),
-            (RegexPat::regex(COMMA,        Punctuation), Arr0),
-            (RegexPat::regex(COMMENT,      Comment),     Text),
-            (RegexPat::regex(NEW_LN,       Text),        Text),
-        ]
-    };
-}
-lazy_static! {
-    static ref ARR1_REG: Vec<(RegexPat<Toml>, Toml)> = {
-        vec![
-            (RegexPat::regex(STRING,       String),      Arr1),
-            (RegexPat::regex(LITERAL,      Literal),     Arr1),
-            (RegexPat::regex(OPEN_ARR,     Punctuation), Arr1),
-            (RegexPat::regex(CLOSE_ARR,    Punctuation), Arr0),
-            (RegexPat::regex(COMMENT,      Comment),     Arr1),
-            (RegexPat::regex(COMMA,        Punctuation), Arr1),
-        ]
-    };
-}
-
-
-impl Toml {
-    pub fn make_parser<'a>() -> Parser<'a, Toml, RegexPat<Toml>> {
-        let mut parser = Parser::new(Text);
-        parser.add_matcher(Text,   &TEXT_REG);
-        parser.add_matcher(Equals, &EQUALS_REG);
-        parser.add_matcher(Arr0,   &ARR0_REG);
-        parser.add_matcher(Arr1,   &ARR1_REG);
-        parser
-    }
+grammar! {
+    Toml: RegexPat<Toml>, default Text;
+
+    Text => [
+        RegexPat::regex(COMMENT,      Comment)     => Text,
+        RegexPat::regex(SECTION,      Section)     => Text,
+        RegexPat::regex(CLOSE_ARR,    Punctuation) => Arr0,
+        RegexPat::regex(COMMA,        Punctuation) => Arr0,
+        RegexPat::regex(NAME,         Name)        => Equals,
+    ],
+    Equals => [
+        RegexPat::regex(EQUALS,       Equals)      => Arr0,
+    ],
+    Arr0 => [
+        RegexPat::regex(STRING,       String)      => Text,
+        RegexPat::regex(LITERAL,      Literal)     => Text,
+        RegexPat::regex(OPEN_ARR,     Punctuation) => Arr1,
+        RegexPat::regex(CLOSE_ARR,    Punctuation) => Arr0,
+        RegexPat::regex(COMMA,        Punctuation) => Arr0,
+        RegexPat::regex(COMMENT,      Comment)     => Text,
+        RegexPat::regex(NEW_LN,       Text)        => Text,
+    ],
+    Arr1 => [
+        RegexPat::regex(STRING,       String)      => Arr1,
+        RegexPat::regex(LITERAL,      Literal)     => Arr1,
+        RegexPat::regex(OPEN_ARR,     Punctuation) => Arr1,
+        RegexPat::regex(CLOSE_ARR,    Punctuation) => Arr0,
+        RegexPat::regex(COMMENT,      Comment)     => Arr1,
+        RegexPat::regex(COMMA,        Punctuation) => Arr1,
+    ],
 }
 
 #[test]
@@ -0,0 +1,69 @@
+use std::ops::Range;
+
+use crate::parse::Highlight;
+
+
+/// A position in the input. Lines are separated by `\n`; lines and columns start at 0.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Position {
+    pub line: usize,
+    /// The column in bytes, i.e. in UTF-8 code units
+    pub column: usize,
+    /// The column in UTF-16 code units, as used by JavaScript and many editors
+    pub utf16_column: usize,
+}
+
+impl Position {
+    /// Returns the position after `text`, if `text` starts at this position.
+    pub fn advance(mut self, text: &str) -> Self {
+        for ch in text.chars() {
+            if ch == '\n' {
+                self.line += 1;
+                self.column = 0;
+                self.utf16_column = 0;
+            } else {
+                self.column += ch.len_utf8();
+                self.utf16_column += ch.len_utf16();
+            }
+        }
+        self
+    }
+}
+
+
+/// A token emitted by the parser.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Token<H: Highlight> {
+    /// The byte range of the token in the input
+    pub range: Range<usize>,
+    pub highlight: H,
+    /// The state of the parser when the token was found
+    pub state: H,
+    pub start: Position,
+    pub end: Position,
+}
+
+impl<H: Highlight> Token<H> {
+    /// Returns the text of the token. `input` must be the string the token was parsed from.
+    pub fn text<'a>(&self, input: &'a str) -> &'a str {
+        &input[self.range.clone()]
+    }
+}
+
+
+/// A line of the input with its tokens, returned by `Parser::lines`.
+/// Tokens that span several lines are split at the line breaks.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Line<'a, H: Highlight> {
+    /// The line number, starting at 0
+    pub number: usize,
+    /// The byte range of the line in the input, without the line break
+    pub range: Range<usize>,
+    /// The state of the parser at the start of the line. If a token continues from the
+    /// previous line, this is the state in which the token was found.
+    pub state: H,
+    /// The tokens of the line, without the line break
+    pub tokens: Vec<(&'a str, H)>,
+    /// The line break at the end of the line: `"\n"`, `"\r\n"`, or `""` in the last line
+    pub line_break: &'a str,
+}
@@ -117,4 +117,1568 @@ pub mod self_impl {
         private String s = "Hello World";
     }"#));
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub mod grammar_errors {
+    use xhighlight::parse::{Highlight, Parser, RegexPat, GrammarError};
+    use xhighlight::syntax::{rust::Rust, toml::Toml, javascript::JS};
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    enum Lang {
+        Text,
+        Keyword,
+        Number,
+    }
+    impl Highlight for Lang {}
+
+    lazy_static! {
+        static ref BROKEN: Result<Vec<(RegexPat<Lang>, Lang)>, GrammarError<Lang>> = {
+            Ok(vec![
+                (RegexPat::try_regex(r"\b(if|else)\b", Lang::Keyword)?, Lang::Text),
+                (RegexPat::try_look_ahead(r"\d+", r"(\s", Lang::Number)?, Lang::Text),
+            ])
+        };
+        static ref EMPTY: Vec<(RegexPat<Lang>, Lang)> = {
+            vec![
+                (RegexPat::regex(r"\b(if|else)\b", Lang::Keyword), Lang::Text),
+                (RegexPat::regex(r"\d*",            Lang::Number),  Lang::Text),
+            ]
+        };
+    }
+
+    #[test]
+    pub fn error_carries_pattern() {
+        let err = RegexPat::try_shortest(r"[a-z", Lang::Keyword).unwrap_err();
+        assert_eq!(err.pattern(), Some("[a-z"));
+        assert_eq!(err.highlight(), Some(Lang::Keyword));
+
+        let err = RegexPat::try_optional(r"\w+", r"\s*(", Lang::Keyword, Lang::Text).unwrap_err();
+        assert_eq!(err.pattern(), Some(r"\s*("));
+    }
+
+    #[test]
+    pub fn broken_pattern_set() {
+        let err = BROKEN.as_ref().unwrap_err();
+        assert_eq!(err.pattern(), Some(r"(\s"));
+        assert_eq!(err.highlight(), Some(Lang::Number));
+    }
+
+    #[test]
+    pub fn try_add_matcher() {
+        let mut parser = Parser::new(Lang::Text);
+        match parser.try_add_matcher(Lang::Keyword, &EMPTY) {
+            Err(GrammarError::EmptyMatch { state: Lang::Keyword, index: 1 }) => {},
+            other => panic!("expected an empty match, got {:?}", other),
+        }
+        assert!(parser.validate().is_ok());
+
+        parser.try_add_matcher(Lang::Text, &EMPTY[.. 1]).unwrap();
+        match parser.try_add_matcher(Lang::Text, &EMPTY[.. 1]) {
+            Err(GrammarError::DuplicateState { state: Lang::Text }) => {},
+            other => panic!("expected a duplicate state, got {:?}", other),
+        }
+        let tokens: Vec<_> = parser.parse("if x").collect();
+        assert_eq!(tokens, vec![("if", Lang::Keyword), (" x", Lang::Text)]);
+    }
+
+    #[test]
+    pub fn built_in_grammars_compile() {
+        Rust::make_parser();
+        Toml::make_parser();
+        JS::make_parser();
+    }
+}
+
+#[cfg(test)]
+pub mod validation {
+    use xhighlight::parse::{Highlight, Parser, RegexPat, GrammarIssue};
+    use xhighlight::syntax::{rust::Rust, toml::Toml, javascript::JS};
+    use self::Lang::*;
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    enum Lang {
+        Text,
+        Keyword,
+        Str,
+        Escape,
+        Comment,
+    }
+    impl Highlight for Lang {}
+
+    lazy_static! {
+        static ref TEXT: Vec<(RegexPat<Lang>, Lang)> = {
+            vec![
+                (RegexPat::regex(r"if|else", Keyword), Text),
+                (RegexPat::regex(r"\w*",     Text),    Text),
+                (RegexPat::regex(r#"""#,     Str),     Str),
+                (RegexPat::regex(r"else",    Keyword), Text),
+            ]
+        };
+        static ref STRING: Vec<(RegexPat<Lang>, Lang)> = {
+            vec![ (RegexPat::regex(r"\\.", Escape), Escape) ]
+        };
+        static ref COMMENT: Vec<(RegexPat<Lang>, Lang)> = {
+            vec![ (RegexPat::regex(r"\*/", Comment), Text) ]
+        };
+    }
+
+    #[test]
+    pub fn reports_issues() {
+        let mut parser = Parser::new(Text);
+        parser.add_matcher(Text, &TEXT)
+            .add_matcher(Str, &STRING)
+            .add_matcher(Comment, &COMMENT);
+
+        let issues = parser.validate().unwrap_err();
+        assert_eq!(issues.len(), 4);
+        assert!(issues.contains(&GrammarIssue::EmptyMatch { state: Text, index: 1 }));
+        assert!(issues.contains(&GrammarIssue::Shadowed { state: Text, index: 3, by: 0 }));
+        assert!(issues.contains(&GrammarIssue::MissingMatcher { state: Str, index: 0, next: Escape }));
+        assert!(issues.contains(&GrammarIssue::Unreachable { state: Comment }));
+    }
+
+    #[test]
+    pub fn empty_matches_dont_shadow() {
+        let rules = vec![
+            (RegexPat::regex(r"a?", Text), Text),
+            (RegexPat::regex(r"b", Text), Text),
+            (RegexPat::regex(r"i", Text), Text),
+            (RegexPat::regex(r"if", Keyword), Text),
+        ];
+        let mut parser = Parser::new(Text);
+        parser.add_matcher(Text, &rules);
+
+        let issues = parser.validate().unwrap_err();
+        assert_eq!(issues, vec![
+            GrammarIssue::EmptyMatch { state: Text, index: 0 },
+            GrammarIssue::Shadowed { state: Text, index: 3, by: 2 },
+        ]);
+    }
+
+    #[test]
+    pub fn built_in_grammars_are_valid() {
+        assert_eq!(Rust::make_parser().validate(), Ok(()));
+        assert_eq!(Toml::make_parser().validate(), Ok(()));
+        assert_eq!(JS::make_parser().validate(), Ok(()));
+    }
+}
+
+#[cfg(test)]
+pub mod fallback {
+    use xhighlight::parse::{Highlight, Parser, RegexPat, Fallback, ParseError};
+    use self::Lang::*;
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    enum Lang {
+        Text,
+        Keyword,
+        Str,
+    }
+    impl Highlight for Lang {}
+
+    lazy_static! {
+        // There's no pattern set for the Str state
+        static ref TEXT: Vec<(RegexPat<Lang>, Lang)> = {
+            vec![
+                (RegexPat::regex(r"let", Keyword), Text),
+                (RegexPat::regex(r#"""#, Str),     Str),
+            ]
+        };
+    }
+
+    fn make_parser<'a>(fallback: Fallback) -> Parser<'a, Lang, RegexPat<Lang>> {
+        let mut parser = Parser::new(Text);
+        parser.add_matcher(Text, &TEXT).set_fallback(fallback);
+        parser
+    }
+
+    #[test]
+    pub fn remainder() {
+        let mut parser = make_parser(Fallback::Remainder);
+        parser.parse(r#"let s = "let";"#);
+        let tokens: Vec<_> = parser.collect();
+        assert_eq!(tokens, vec![
+            ("let", Keyword), (" s = ", Text), ("\"", Str), ("let\";", Str),
+        ]);
+    }
+
+    #[test]
+    pub fn default_state() {
+        let mut parser = make_parser(Fallback::DefaultState);
+        parser.parse(r#"let s = "let";"#);
+        let tokens: Vec<_> = parser.collect();
+        assert_eq!(tokens, vec![
+            ("let", Keyword), (" s = ", Text), ("\"", Str), ("let", Keyword), ("\"", Str), (";", Text),
+        ]);
+    }
+
+    #[test]
+    pub fn fail() {
+        let mut parser = make_parser(Fallback::Fail);
+        parser.parse(r#"let s = "let";"#);
+        assert_eq!(parser.try_next_match(), Ok(Some(("let", Keyword))));
+        assert_eq!(parser.try_next_match(), Ok(Some((" s = ", Text))));
+        assert_eq!(parser.try_next_match(), Ok(Some(("\"", Str))));
+        assert_eq!(parser.try_next_match(), Err(ParseError::MissingMatcher { state: Str, offset: 9 }));
+        assert_eq!(parser.next_match(), None);
+    }
+}
+
+#[cfg(test)]
+pub mod tokens {
+    use xhighlight::syntax::rust::Rust;
+    use xhighlight::token::{Position, Token};
+
+    #[test]
+    pub fn positions() {
+        let input = "let s = \"ä𝄞\";\n// x";
+        let mut parser = Rust::make_parser();
+        parser.parse(input);
+        let tokens: Vec<Token<Rust>> = parser.tokens().collect();
+
+        let string_end = &tokens[8];
+        assert_eq!(string_end.text(input), "\"");
+        assert_eq!(string_end.highlight, Rust::String);
+        assert_eq!(string_end.state, Rust::String);
+        assert_eq!(string_end.range, 15 .. 16);
+        assert_eq!(string_end.start, Position { line: 0, column: 15, utf16_column: 12 });
+
+        let comment = tokens.last().unwrap();
+        assert_eq!(comment.text(input), "// x");
+        assert_eq!(comment.highlight, Rust::LineComment);
+        assert_eq!(comment.start, Position { line: 1, column: 0, utf16_column: 0 });
+        assert_eq!(comment.end, Position { line: 1, column: 4, utf16_column: 4 });
+
+        let text: String = tokens.iter().map(|t| t.text(input)).collect();
+        assert_eq!(text, input);
+    }
+}
+
+#[cfg(test)]
+pub mod stack {
+    use xhighlight::parse::{Highlight, Parser, RegexPat, Transition::{self, Push, Pop}};
+    use xhighlight::syntax::{rust::Rust, javascript::JS};
+
+    #[test]
+    pub fn nested_block_comments() {
+        let mut parser = Rust::make_parser();
+        parser.parse("/* a /* b */ c */ x");
+        let tokens: Vec<_> = parser.collect();
+        assert_eq!(tokens, vec![
+            ("/*", Rust::BlockComment), (" a ", Rust::BlockComment),
+            ("/*", Rust::BlockComment), (" b ", Rust::BlockComment),
+            ("*/", Rust::BlockComment), (" c ", Rust::BlockComment),
+            ("*/", Rust::BlockComment), (" ", Rust::Text), ("x", Rust::Identifier),
+        ]);
+    }
+
+    #[test]
+    pub fn template_strings() {
+        let mut parser = JS::make_parser();
+        parser.parse("`a ${ {b: 1} } c` + d");
+        let tokens: Vec<_> = parser.collect();
+        assert_eq!(tokens, vec![
+            ("`", JS::TemplateString), ("a ", JS::TemplateString), ("${", JS::TemplateString),
+            (" ", JS::TplInner), ("{", JS::Punctuation), ("b", JS::Identifier),
+            (":", JS::Operator), (" ", JS::Text), ("1", JS::Number), ("} ", JS::Punctuation),
+            ("}", JS::TemplateString), (" c", JS::TemplateString), ("`", JS::TemplateString),
+            (" ", JS::Text), ("+", JS::Operator), (" ", JS::Text), ("d", JS::Identifier),
+        ]);
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    enum Lang {
+        Text,
+        Paren,
+    }
+    impl Highlight for Lang {}
+
+    lazy_static! {
+        static ref PARENS: Vec<(RegexPat<Lang>, Transition<Lang>)> = {
+            vec![
+                (RegexPat::regex(r"\(", Lang::Paren), Push(Lang::Paren)),
+                (RegexPat::regex(r"\)", Lang::Paren), Pop(1)),
+            ]
+        };
+    }
+
+    #[test]
+    pub fn max_depth() {
+        let mut parser = Parser::new(Lang::Text);
+        parser.add_stack_matcher(Lang::Text, &PARENS)
+            .add_stack_matcher(Lang::Paren, &PARENS)
+            .set_max_depth(2);
+        parser.parse("((( a ))) b ) c");
+        let tokens: Vec<_> = parser.collect();
+        // The third `(` isn't pushed, so the third `)` already returns to Text
+        assert_eq!(tokens[3], (" a ", Lang::Paren));
+        assert_eq!(tokens[6], (")", Lang::Paren));
+        assert_eq!(tokens[7], (" b ", Lang::Text));
+        assert_eq!(tokens[9], (" c", Lang::Text));
+    }
+}
+
+#[cfg(test)]
+pub mod snapshots {
+    use xhighlight::syntax::rust::Rust;
+
+    const INPUT: &str = "fn main() { /* a\n /* b */\n c */ let s = \"x\\n\";\n}\n";
+
+    /// Returns the highlight of every byte
+    fn highlights<'a>(tokens: impl Iterator<Item = (&'a str, Rust)>) -> Vec<Rust> {
+        tokens.flat_map(|(s, hl)| std::iter::repeat_n(hl, s.len())).collect()
+    }
+
+    #[test]
+    pub fn line_by_line() {
+        let mut parser = Rust::make_parser();
+        parser.parse(INPUT);
+        let expected = highlights(&mut parser);
+
+        let mut actual = Vec::new();
+        let mut state = Rust::make_parser().snapshot();
+        for line in INPUT.split_inclusive('\n') {
+            let mut parser = Rust::make_parser();
+            parser.parse(line).restore(&state);
+            actual.extend(highlights(&mut parser));
+            state = parser.snapshot();
+        }
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    pub fn compare_and_resume() {
+        let mut parser = Rust::make_parser();
+        parser.parse(INPUT);
+        let start = parser.snapshot();
+        let mut first = Vec::new();
+        while let Some(token) = parser.next_match() {
+            first.push(token);
+            if token == (" a\n ", Rust::BlockComment) {
+                break;
+            }
+        }
+        let inside = parser.snapshot();
+        assert_ne!(start, inside);
+        assert_eq!(inside.state(), Rust::BlockComment);
+        let rest: Vec<_> = parser.collect();
+
+        let mut other = Rust::make_parser();
+        other.parse(INPUT);
+        other.offset(first.iter().map(|(s, _)| s.len()).sum()).unwrap();
+        other.restore(&inside);
+        assert_eq!(other.snapshot(), inside);
+        assert_eq!(other.collect::<Vec<_>>(), rest);
+    }
+}
+
+#[cfg(test)]
+pub mod incremental {
+    use xhighlight::incremental::{Incremental, LineChange};
+    use xhighlight::parse::{Highlight, Parser, Pattern, RegexPat};
+    use xhighlight::syntax::{rust::Rust, javascript::JS, toml::Toml};
+
+    const INPUT: &str = "fn main() {\n    let a = 1;\n    /* comment\n    */\n    let s = \"x\";\n}\n";
+
+    fn lines<H: Highlight, P: Pattern<H>>(doc: &Incremental<H, P>) -> Vec<Vec<(String, H)>> {
+        (0 .. doc.line_count())
+            .map(|i| doc.line(i).map(|(s, hl)| (s.to_string(), hl)).collect())
+            .collect()
+    }
+
+    #[test]
+    pub fn same_as_full_parse() {
+        let edits: &[(std::ops::Range<usize>, &str)] = &[
+            (15 .. 16, "/*"),
+            (15 .. 17, ""),
+            (0 .. 0, "// x\n"),
+            (20 .. 24, "    let b = \"\n"),
+            (33 .. 34, ""),
+            (0 .. 5, ""),
+            (10 .. 30, "ä\n\n"),
+        ];
+        let mut doc = Incremental::new(Rust::make_parser(), INPUT.to_string());
+        for (range, text) in edits {
+            doc.edit(range.clone(), text);
+            let expected = Incremental::new(Rust::make_parser(), doc.text().to_string());
+            assert_eq!(lines(&doc), lines(&expected), "after replacing {:?} with {:?}", range, text);
+        }
+    }
+
+    /// xorshift, so the random tests are deterministic
+    fn random(seed: &mut u64, n: usize) -> usize {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        (*seed % n as u64) as usize
+    }
+
+    /// Checks that random edits of text made of `pieces` give the same result as a full parse
+    fn random_edits<H: Highlight, P: Pattern<H>>(make_parser: fn() -> Parser<'static, H, P>, pieces: &[&str]) {
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        for _ in 0 .. 5000 {
+            let text: String = (0 .. random(&mut seed, 24)).map(|_| pieces[random(&mut seed, pieces.len())]).collect();
+            let insert: String = (0 .. random(&mut seed, 3)).map(|_| pieces[random(&mut seed, pieces.len())]).collect();
+            let boundaries: Vec<usize> = (0 ..= text.len()).filter(|&i| text.is_char_boundary(i)).collect();
+            let a = boundaries[random(&mut seed, boundaries.len())];
+            let b = boundaries[random(&mut seed, boundaries.len())];
+            let range = a.min(b) .. a.max(b);
+
+            let mut doc = Incremental::new(make_parser(), text.clone());
+            doc.edit(range.clone(), &insert);
+            let expected = Incremental::new(make_parser(), doc.text().to_string());
+            assert_eq!(lines(&doc), lines(&expected), "{:?}: replacing {:?} with {:?}", text, range, insert);
+        }
+    }
+
+    #[test]
+    pub fn random_edits_same_as_full_parse() {
+        random_edits(Rust::make_parser, &[
+            "a", "ä", "fn", "r", "#", " ", "\n", "\"", "\\", "/", "*", "1", "'", "(", ":", "<", "!",
+        ]);
+        random_edits(JS::make_parser, &[
+            "a", "x", "if", " ", "\n", "\"", "'", "`", "$", "{", "}", "\\", "/", "*", "1", "(", "=",
+        ]);
+        random_edits(Toml::make_parser, &[
+            "a", " ", "\n", "\"", "'", "#", "[", "]", "=", "1", "\\", ".", "-", ":",
+        ]);
+    }
+
+    /// Asserts that an edit gives the same result as parsing the new text
+    fn assert_edit<H: Highlight, P: Pattern<H>>(make_parser: fn() -> Parser<'static, H, P>,
+                                                 text: &str, range: std::ops::Range<usize>, insert: &str) {
+        let mut doc = Incremental::new(make_parser(), text.to_string());
+        doc.edit(range, insert);
+        let expected = Incremental::new(make_parser(), doc.text().to_string());
+        assert_eq!(lines(&doc), lines(&expected));
+    }
+
+    #[test]
+    pub fn edit_in_look_ahead() {
+        // `foo` is only a function call if it's followed by `(`
+        assert_edit(Rust::make_parser, "a\n    foo (1)\n", 10 .. 11, "");
+        assert_edit(JS::make_parser, "\nax\n(a", 4 .. 6, "");
+        // The string that wasn't closed at the end of the first line now continues
+        assert_edit(Toml::make_parser, "]\"## a[\n]=[1", 10 .. 11, "\"a");
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    enum Lang {
+        Text,
+        Call,
+        Name,
+    }
+    impl Highlight for Lang {}
+
+    lazy_static! {
+        static ref OPTIONAL: Vec<(RegexPat<Lang>, Lang)> = vec![
+            (RegexPat::optional(r"\w+", r"\s*\(", Lang::Call, Lang::Name), Lang::Text),
+        ];
+    }
+
+    fn optional_parser() -> Parser<'static, Lang, RegexPat<Lang>> {
+        let mut parser = Parser::new(Lang::Text);
+        parser.add_matcher(Lang::Text, &OPTIONAL);
+        parser
+    }
+
+    #[test]
+    pub fn edit_in_optional_look_ahead() {
+        // The look-ahead spans several lines
+        let text = "a\nb\n\n\n  (";
+        for i in 0 .. text.len() {
+            assert_edit(optional_parser, text, i .. i + 1, "");
+            assert_edit(optional_parser, text, i .. i, "x");
+        }
+        random_edits(optional_parser, &["a", " ", "\n", "(", ")"]);
+    }
+
+    #[test]
+    pub fn changed_lines() {
+        let mut doc = Incremental::new(Rust::make_parser(), INPUT.to_string());
+        assert_eq!(doc.line_count(), 7);
+
+        // Only the edited line changes
+        let pos = doc.text().find("a = 1").unwrap();
+        let change = doc.edit(pos .. pos + 1, "bc");
+        assert_eq!(change, LineChange { old: 1 .. 2, new: 1 .. 2 });
+
+        // A line break is inserted
+        let pos = doc.text().find("1;").unwrap() + 2;
+        let change = doc.edit(pos .. pos, "\n");
+        assert_eq!(change, LineChange { old: 1 .. 2, new: 1 .. 3 });
+        assert_eq!(doc.line_count(), 8);
+
+        // The comment is removed, which changes the line after it as well
+        let pos = doc.text().find("/*").unwrap();
+        let change = doc.edit(pos .. pos + 2, "");
+        assert_eq!(change, LineChange { old: 3 .. 5, new: 3 .. 5 });
+        assert_eq!(doc.line(4).collect::<Vec<_>>(), vec![
+            ("    ", Rust::Text), ("*", Rust::Operator), ("/", Rust::Operator), ("\n", Rust::Text),
+        ]);
+    }
+
+    #[test]
+    pub fn template_strings() {
+        let input = "let a = `x ${ {b: 1} }\ny`;\nc";
+        let mut doc = Incremental::new(JS::make_parser(), input.to_string());
+        doc.edit(13 .. 14, "");
+        let expected = Incremental::new(JS::make_parser(), doc.text().to_string());
+        for i in 0 .. doc.line_count() {
+            assert!(doc.line(i).eq(expected.line(i)));
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod captures {
+    use xhighlight::parse::{Highlight, Parser, RegexPat, GrammarError};
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    enum Lang {
+        Text,
+        Keyword,
+        Punctuation,
+        Key,
+        Value,
+    }
+    impl Highlight for Lang {}
+
+    lazy_static! {
+        static ref TEXT: Vec<(RegexPat<Lang>, Lang)> = {
+            vec![
+                (RegexPat::captures(r"(fn)\s+(?P<name>\w+)(\()",
+                    &[(1, Lang::Keyword), (3, Lang::Punctuation)], Lang::Text), Lang::Text),
+                (RegexPat::captures(r"(?P<key>\w+)\s*=\s*(?P<value>\w+)",
+                    &[("value", Lang::Value), ("key", Lang::Key)], Lang::Punctuation), Lang::Text),
+            ]
+        };
+    }
+
+    fn parse(input: &str) -> Vec<(&str, Lang)> {
+        let mut parser = Parser::new(Lang::Text);
+        parser.add_matcher(Lang::Text, &TEXT);
+        parser.parse(input);
+        parser.collect()
+    }
+
+    #[test]
+    pub fn numbered_groups() {
+        assert_eq!(parse("x fn foo()"), vec![
+            ("x ", Lang::Text), ("fn", Lang::Keyword), (" foo", Lang::Text),
+            ("(", Lang::Punctuation), (")", Lang::Text),
+        ]);
+    }
+
+    #[test]
+    pub fn named_groups() {
+        // Groups are emitted in the order in which they occur in the input
+        assert_eq!(parse("a = b"), vec![
+            ("a", Lang::Key), (" = ", Lang::Punctuation), ("b", Lang::Value),
+        ]);
+    }
+
+    #[test]
+    pub fn ignored_groups() {
+        // Group 2 is nested in group 1, group 3 doesn't participate in the match
+        let pat = RegexPat::captures(r"(a(b))c(d)?", &[(1, Lang::Key), (2, Lang::Value), (3, Lang::Value)],
+                                     Lang::Text);
+        let m = xhighlight::parse::Pattern::get_match(&pat, "abc", 0, Lang::Text).unwrap();
+        assert_eq!(m.groups, vec![(0 .. 2, Lang::Key)]);
+        assert_eq!(m.end, 3);
+    }
+
+    #[test]
+    pub fn unknown_groups() {
+        match RegexPat::try_captures(r"(a)", &[(2, Lang::Key)], Lang::Text) {
+            Err(GrammarError::UnknownGroup { group, pattern, highlight }) => {
+                assert_eq!((group.as_str(), pattern.as_str(), highlight), ("2", "(a)", Lang::Text));
+            },
+            _ => panic!("expected an unknown group"),
+        }
+        match RegexPat::try_captures(r"(?P<a>a)", &[("b", Lang::Key)], Lang::Text) {
+            Err(GrammarError::UnknownGroup { group, .. }) => assert_eq!(group, "b"),
+            _ => panic!("expected an unknown group"),
+        }
+    }
+
+    #[test]
+    pub fn snapshot_within_match() {
+        let mut parser = Parser::new(Lang::Text);
+        parser.add_matcher(Lang::Text, &TEXT);
+        parser.parse("a = b");
+        parser.next_match();
+        let state = parser.snapshot();
+        let rest: Vec<_> = parser.collect();
+
+        let input = "a = b";
+        let mut other = Parser::new(Lang::Text);
+        other.add_matcher(Lang::Text, &TEXT);
+        other.parse(input);
+        other.offset(1).unwrap();
+        other.restore(&state);
+        assert_eq!(other.collect::<Vec<_>>(), rest);
+    }
+}
+
+#[cfg(test)]
+pub mod first_bytes {
+    use xhighlight::parse::{Highlight, Parser, Pattern, RegexPat};
+    use xhighlight::syntax::{rust::Rust, javascript::JS, toml::Toml};
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    enum Lang {
+        Text,
+        Word,
+        Number,
+        Short,
+    }
+    impl Highlight for Lang {}
+
+    lazy_static! {
+        static ref TEXT: Vec<(RegexPat<Lang>, Lang)> = {
+            vec![
+                (RegexPat::regex(r"ab",       Lang::Short),  Lang::Text),
+                (RegexPat::regex(r"\b\w+",    Lang::Word),   Lang::Text),
+                (RegexPat::regex(r"(?i)x?Ö+", Lang::Short),  Lang::Text),
+                (RegexPat::regex(r"[0-9]+",   Lang::Number), Lang::Text),
+            ]
+        };
+    }
+
+    #[test]
+    pub fn pattern_first_bytes() {
+        let first = TEXT[3].0.first_bytes().unwrap();
+        assert!(first.contains(b'0') && first.contains(b'9'));
+        assert!(!first.contains(b'a') && !first.contains(b' '));
+
+        // The `x` is optional, and `(?i)` also matches `ö`
+        let first = TEXT[2].0.first_bytes().unwrap();
+        assert!(first.contains(b'x') && first.contains(b'X'));
+        assert!(first.contains("Ö".as_bytes()[0]) && first.contains("ö".as_bytes()[0]));
+        assert!(!first.contains(b'a'));
+    }
+
+    #[test]
+    pub fn first_pattern_wins() {
+        let mut parser = Parser::new(Lang::Text);
+        parser.add_matcher(Lang::Text, &TEXT);
+        parser.parse("  abc - äö 12 ÖÖ!");
+        let tokens: Vec<_> = parser.collect();
+        assert_eq!(tokens, vec![
+            ("  ", Lang::Text), ("ab", Lang::Short), ("c", Lang::Word), (" - ", Lang::Text),
+            ("äö", Lang::Word), (" ", Lang::Text), ("12", Lang::Word), (" ", Lang::Text),
+            ("ÖÖ", Lang::Word), ("!", Lang::Text),
+        ]);
+    }
+
+    fn text_parser() -> Parser<'static, Lang, RegexPat<Lang>> {
+        let mut parser = Parser::new(Lang::Text);
+        parser.add_matcher(Lang::Text, &TEXT);
+        parser
+    }
+
+    /// Asserts that the combined search gives the same tokens as the search by first bytes
+    fn assert_combined_search<H: Highlight, P: Pattern<H>>(make_parser: fn() -> Parser<'static, H, P>, input: &'static str) {
+        let expected: Vec<_> = make_parser().parse(input).collect();
+        let mut parser = make_parser();
+        parser.set_combined_search(true);
+        let tokens: Vec<_> = parser.parse(input).collect();
+        assert_eq!(tokens, expected);
+    }
+
+    #[test]
+    pub fn combined_search() {
+        assert_combined_search(text_parser, "  abc - äö 12 ÖÖ!");
+        assert_combined_search(Rust::make_parser, include_str!("../src/parse.rs"));
+        assert_combined_search(JS::make_parser, "let a = `x${b / 2}` / c; // d\nif (/re[/]/g.test(s)) { f('\\\\'); }\n");
+        assert_combined_search(Toml::make_parser, "[a.b]\nx = \"s\\n\" # c\ny = [1, 2.5, true, 1979-05-27]\nz = '''\nlit\n'''\n");
+    }
+}
+
+#[cfg(test)]
+pub mod grammar {
+    use std::sync::Arc;
+    use std::thread;
+    use xhighlight::parse::{Grammar, Highlight, RegexPat};
+    use xhighlight::render::{HtmlRenderer, Renderer, SharedHtmlRenderer};
+    use xhighlight::syntax::rust::Rust;
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    enum Lang {
+        Text,
+        Number,
+    }
+    impl Highlight for Lang {}
+
+    fn is_send_sync<T: Send + Sync>(_: &T) {}
+
+    #[test]
+    pub fn owned_patterns() {
+        let mut grammar = Grammar::new(Lang::Text);
+        grammar.add_matcher(Lang::Text, vec![
+            (RegexPat::regex(r"\d+", Lang::Number), Lang::Text),
+        ]);
+        assert!(grammar.validate().is_ok());
+
+        // Inputs don't need to live as long as the grammar
+        let input = String::from("a 1 b");
+        let tokens: Vec<_> = grammar.parser().parse(&input).collect();
+        assert_eq!(tokens, vec![("a ", Lang::Text), ("1", Lang::Number), (" b", Lang::Text)]);
+    }
+
+    #[test]
+    pub fn shared_renderer() {
+        let renderer = Arc::new(SharedHtmlRenderer::new(Rust::grammar())
+            .set_mapping(&super::RUST_CSS));
+        is_send_sync(&renderer);
+
+        let mut parser = Rust::make_parser();
+        let expected = HtmlRenderer::new(&mut parser)
+            .set_mapping(&super::RUST_CSS)
+            .render(super::TEST_STR);
+
+        let threads: Vec<_> = (0 .. 4).map(|_| {
+            let renderer = renderer.clone();
+            thread::spawn(move || renderer.render(super::TEST_STR))
+        }).collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), expected);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod word_boundaries {
+    use xhighlight::parse::is_word_boundary;
+    use xhighlight::syntax::{rust::Rust, javascript::JS};
+
+    #[test]
+    pub fn boundaries() {
+        assert!(is_word_boundary("ab cd", 0));
+        assert!(!is_word_boundary("ab cd", 1));
+        assert!(is_word_boundary("ab cd", 2));
+        assert!(is_word_boundary("ab cd", 5));
+        assert!(!is_word_boundary("a  b", 2));
+        assert!(!is_word_boundary("", 0));
+        // Non-ASCII word characters, indices within a char and out of bounds
+        assert!(!is_word_boundary("äb", 2));
+        assert!(!is_word_boundary("äb", 1));
+        assert!(!is_word_boundary("ab", 3));
+    }
+
+    #[test]
+    pub fn keywords_within_words() {
+        let mut parser = Rust::make_parser();
+        parser.parse("1as 0xfor in");
+        let tokens: Vec<_> = parser.collect();
+        assert_eq!(tokens, vec![
+            ("1", Rust::Number), ("as", Rust::Identifier), (" ", Rust::Text),
+            ("0xf", Rust::Number), ("or", Rust::Identifier), (" ", Rust::Text), ("in", Rust::Keyword),
+        ]);
+
+        let mut parser = JS::make_parser();
+        parser.parse("$in x$do 0xfin true");
+        let tokens: Vec<_> = parser.collect();
+        assert_eq!(tokens, vec![
+            ("$in", JS::Identifier), (" ", JS::Text), ("x$do", JS::Identifier), (" ", JS::Text),
+            ("0xf", JS::Number), ("in", JS::Identifier), (" ", JS::Text), ("true", JS::Bool),
+        ]);
+    }
+}
+
+#[cfg(test)]
+pub mod context {
+    use xhighlight::parse::{Context, Highlight, Parser, RegexPat};
+    use xhighlight::syntax::javascript::JS;
+
+    fn js(input: &str) -> Vec<(&str, JS)> {
+        let mut parser = JS::make_parser();
+        parser.parse(input);
+        parser.collect()
+    }
+
+    #[test]
+    pub fn regex_or_division() {
+        assert_eq!(js("a = /b/g"), vec![
+            ("a", JS::Identifier), (" ", JS::Text), ("=", JS::Operator), (" ", JS::Text),
+            ("/b/g", JS::Regex),
+        ]);
+        assert_eq!(js("(a) / b / c"), vec![
+            ("(", JS::Punctuation), ("a", JS::Identifier), (") ", JS::Punctuation),
+            ("/", JS::Operator), (" ", JS::Text), ("b", JS::Identifier), (" ", JS::Text),
+            ("/", JS::Operator), (" ", JS::Text), ("c", JS::Identifier),
+        ]);
+        // Comments are skipped
+        assert_eq!(js("return /* x */ /y/"), vec![
+            ("return", JS::Keyword), (" ", JS::Text), ("/* x */", JS::BlockComment),
+            (" ", JS::Text), ("/y/", JS::Regex),
+        ]);
+        assert_eq!(js("/a/.test(x)")[0], ("/a/", JS::Regex));
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    enum Lang {
+        Text,
+        Word,
+        Comment,
+        Third,
+    }
+    impl Highlight for Lang {}
+
+    fn third_word(context: &Context<Lang>) -> bool {
+        context.previous(0).is_some_and(|(_, hl)| hl == Lang::Word)
+            && context.previous(1) == Some(("a", Lang::Word))
+            && context.previous(2).is_none()
+    }
+
+    lazy_static! {
+        static ref TEXT: Vec<(RegexPat<Lang>, Lang)> = {
+            vec![
+                (RegexPat::regex(r"#.*", Lang::Comment), Lang::Text),
+                (RegexPat::after(r"\w+", third_word, Lang::Third), Lang::Text),
+                (RegexPat::regex(r"[a-z]+", Lang::Word), Lang::Text),
+            ]
+        };
+    }
+
+    #[test]
+    pub fn previous_tokens() {
+        let mut parser = Parser::new(Lang::Text);
+        parser.add_matcher(Lang::Text, &TEXT)
+            .set_context_window(2, &[Lang::Comment]);
+        // The comment is skipped, so `c` is the third word. The text before `d` contains `1`,
+        // so it is the last token when `d` is matched
+        parser.parse("a #c\n b c 1 d");
+        let tokens: Vec<_> = parser.collect();
+        assert_eq!(tokens, vec![
+            ("a", Lang::Word), (" ", Lang::Text), ("#c", Lang::Comment), ("\n ", Lang::Text),
+            ("b", Lang::Word), (" ", Lang::Text), ("c", Lang::Third), (" 1 ", Lang::Text),
+            ("d", Lang::Word),
+        ]);
+    }
+
+    #[test]
+    pub fn snapshots_contain_context() {
+        let mut parser = JS::make_parser();
+        parser.parse("x = 1 / 2");
+        for _ in 0 .. 5 {
+            parser.next_match();
+        }
+        let state = parser.snapshot();
+
+        let mut other = JS::make_parser();
+        other.parse("x = ( / 2/");
+        other.offset(5).unwrap();
+        other.restore(&state);
+        // The last token is `1`, not `(`, so this is a division
+        assert_eq!(other.next_match(), Some((" ", JS::Text)));
+        assert_eq!(other.next_match(), Some(("/", JS::Operator)));
+    }
+}
+
+#[cfg(test)]
+pub mod scanners {
+    use xhighlight::parse::{AnyPat, FnPat, Highlight, Parser, RegexPat};
+    use xhighlight::syntax::rust::Rust;
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    enum Lang {
+        Text,
+        Word,
+        Heading,
+        Level,
+    }
+    impl Highlight for Lang {}
+
+    /// A heading is highlighted depending on the number of `#`
+    fn heading(s: &str, index: usize) -> Option<(usize, Lang)> {
+        let level = s[index ..].bytes().take_while(|&b| b == b'#').count();
+        match level {
+            0 => None,
+            1 => Some((index + 1, Lang::Heading)),
+            _ => Some((index + level, Lang::Level)),
+        }
+    }
+
+    lazy_static! {
+        static ref TEXT: Vec<(AnyPat<Lang>, Lang)> = {
+            vec![
+                (FnPat::with_highlight(heading, Lang::Heading).set_first_bytes(b"#").into(), Lang::Text),
+                (FnPat::new(|_, _| Some(0), Lang::Word).into(), Lang::Text),
+                (RegexPat::regex(r"[a-z]+", Lang::Word).into(), Lang::Text),
+            ]
+        };
+    }
+
+    #[test]
+    pub fn mixed_patterns() {
+        let mut parser = Parser::new(Lang::Text);
+        parser.add_matcher(Lang::Text, &TEXT);
+        // The second pattern always returns an empty match, which is ignored
+        parser.parse("# a\n### b");
+        let tokens: Vec<_> = parser.collect();
+        assert_eq!(tokens, vec![
+            ("#", Lang::Heading), (" ", Lang::Text), ("a", Lang::Word), ("\n", Lang::Text),
+            ("###", Lang::Level), (" ", Lang::Text), ("b", Lang::Word),
+        ]);
+    }
+
+    #[test]
+    pub fn rust_raw_strings() {
+        let mut parser = Rust::make_parser();
+        parser.parse(r###############"r"a" + br##"b"#"## + r############"c"############ + r#"d"###############);
+        let tokens: Vec<_> = parser.collect();
+        assert_eq!(tokens, vec![
+            (r#"r"a""#, Rust::String), (" ", Rust::Text), ("+", Rust::Operator), (" ", Rust::Text),
+            (r###"br##"b"#"##"###, Rust::String), (" ", Rust::Text), ("+", Rust::Operator), (" ", Rust::Text),
+            (r##############"r############"c"############"##############, Rust::String),
+            (" ", Rust::Text), ("+", Rust::Operator), (" ", Rust::Text),
+            // Unterminated
+            ("r", Rust::Identifier), ("#", Rust::Text), ("\"", Rust::String), ("d", Rust::String),
+        ]);
+    }
+}
+
+#[cfg(test)]
+pub mod keywords {
+    use xhighlight::parse::{AnyPat, Highlight, KeywordPat, Parser, RegexPat};
+    use xhighlight::syntax::javascript::JS;
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    enum Sql {
+        Text,
+        Keyword,
+        Name,
+    }
+    impl Highlight for Sql {}
+
+    lazy_static! {
+        static ref TEXT: Vec<(AnyPat<Sql>, Sql)> = {
+            vec![
+                (KeywordPat::ignore_case(&["select", "from", "where"], Sql::Keyword).into(), Sql::Text),
+                (RegexPat::regex(r"\w+", Sql::Name).into(), Sql::Text),
+            ]
+        };
+    }
+
+    #[test]
+    pub fn case_insensitive() {
+        let mut parser = Parser::new(Sql::Text);
+        parser.add_matcher(Sql::Text, &TEXT);
+        parser.parse("SELECT a FrOm selection where_ b");
+        let tokens: Vec<_> = parser.collect();
+        assert_eq!(tokens, vec![
+            ("SELECT", Sql::Keyword), (" ", Sql::Text), ("a", Sql::Name), (" ", Sql::Text),
+            ("FrOm", Sql::Keyword), (" ", Sql::Text), ("selection", Sql::Name), (" ", Sql::Text),
+            ("where_", Sql::Name), (" ", Sql::Text), ("b", Sql::Name),
+        ]);
+    }
+
+    #[test]
+    pub fn word_chars() {
+        let mut parser = JS::make_parser();
+        parser.parse("if $if if$ x.if");
+        let tokens: Vec<_> = parser.collect();
+        assert_eq!(tokens, vec![
+            ("if", JS::Keyword), (" ", JS::Text), ("$if", JS::Identifier), (" ", JS::Text),
+            ("if$", JS::Identifier), (" ", JS::Text), ("x", JS::Identifier),
+            (".", JS::Punctuation), ("if", JS::Keyword),
+        ]);
+    }
+}
+
+#[cfg(test)]
+pub mod stream {
+    use std::io::BufReader;
+    use xhighlight::stream::TokenStream;
+    use xhighlight::syntax::javascript::JS;
+    use xhighlight::syntax::rust::Rust;
+
+    #[test]
+    pub fn same_tokens() {
+        let input = super::TEST_STR.repeat(20);
+        let mut parser = Rust::make_parser();
+        parser.parse(&input);
+        let expected: Vec<_> = parser.map(|(s, hl)| (s.to_string(), hl)).collect();
+
+        // Tokens straddle the chunks of the reader and the lookahead
+        for &lookahead in &[1, 100, 1000, 100_000] {
+            let reader = BufReader::with_capacity(7, input.as_bytes());
+            let tokens = TokenStream::new(Rust::make_parser(), reader)
+                .set_lookahead(lookahead)
+                .collect::<std::io::Result<Vec<_>>>()
+                .unwrap();
+            if lookahead >= 100 {
+                assert_eq!(tokens, expected);
+            } else {
+                // The raw string is longer than the lookahead, but no text is lost
+                let text: String = tokens.iter().map(|(s, _)| s.as_str()).collect();
+                assert_eq!(text, input);
+            }
+        }
+    }
+
+    #[test]
+    pub fn state_across_chunks() {
+        // The template string and the context window continue in the next chunks
+        let input = "let a = `x ${ b /\n 2 }\n y`;\nc = /\nd/g";
+        let mut parser = JS::make_parser();
+        parser.parse(input);
+        let expected: Vec<_> = parser.map(|(s, hl)| (s.to_string(), hl)).collect();
+
+        let tokens = TokenStream::new(JS::make_parser(), input.as_bytes())
+            .set_lookahead(3)
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(tokens, expected);
+    }
+
+    #[test]
+    pub fn invalid_utf8() {
+        let input: &[u8] = b"fn a() {}\n\xff\n\xe2\x82";
+        let tokens = TokenStream::new(Rust::make_parser(), input)
+            .set_lookahead(100)
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        let text: String = tokens.iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(text, "fn a() {}\n\u{FFFD}\n\u{FFFD}");
+    }
+
+    #[test]
+    pub fn chars_across_chunks() {
+        let input = "// äöü €\nlet a = \"ÿ€\";".repeat(10);
+        let reader = BufReader::with_capacity(1, input.as_bytes());
+        let tokens = TokenStream::new(Rust::make_parser(), reader)
+            .set_lookahead(1)
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        let text: String = tokens.iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(text, input);
+    }
+
+    #[test]
+    pub fn long_token() {
+        // A comment without line breaks that is much longer than the lookahead
+        let input = format!("/* {} */", "a".repeat(1_000_000));
+        let tokens = TokenStream::new(Rust::make_parser(), input.as_bytes())
+            .set_lookahead(10)
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        let text: String = tokens.iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(text, input);
+    }
+}
+
+#[cfg(test)]
+pub mod filter {
+    use xhighlight::filter::{Coalesce, Pipeline, Reclassify, SplitLines, TokenFilter};
+    use xhighlight::syntax::rust::Rust;
+    use xhighlight::token::Token;
+
+    /// Removes whitespace-only tokens
+    struct SkipWhitespace;
+
+    impl TokenFilter<Rust> for SkipWhitespace {
+        fn filter(&mut self, input: &str, token: Token<Rust>, emit: &mut dyn FnMut(Token<Rust>)) {
+            if !token.text(input).trim().is_empty() {
+                emit(token);
+            }
+        }
+    }
+
+    #[test]
+    pub fn builtin_filters() {
+        let input = "let x = my_fn(1);\n/* a\n\nb */";
+        let mut parser = Rust::make_parser();
+        parser.parse(input);
+        let mut pipeline = Pipeline::new()
+            .add_filter(Reclassify::new(Rust::FnCall, &[("my_fn", Rust::MacroCall)]))
+            .add_filter(Coalesce::new())
+            .add_filter(SplitLines);
+        let tokens: Vec<_> = pipeline.apply(input, parser.tokens()).collect();
+        assert_eq!(tokens, vec![
+            ("let", Rust::Keyword), (" ", Rust::Text), ("x", Rust::Identifier), (" ", Rust::Text),
+            ("=", Rust::Operator), (" ", Rust::Text), ("my_fn", Rust::MacroCall),
+            ("(", Rust::Punctuation), ("1", Rust::Number), (");", Rust::Punctuation), ("\n", Rust::Text),
+            ("/* a\n", Rust::BlockComment), ("\n", Rust::BlockComment), ("b */", Rust::BlockComment),
+        ]);
+    }
+
+    #[test]
+    pub fn positions() {
+        let input = "a\nb\nc";
+        let mut parser = Rust::make_parser();
+        parser.parse(input);
+        let mut pipeline = Pipeline::new().add_filter(Coalesce::new()).add_filter(SplitLines);
+        let mut filtered = pipeline.apply(input, parser.tokens());
+        let mut lines = Vec::new();
+        while let Some(token) = filtered.next_token() {
+            lines.push((token.start.line, token.end.line, token.end.column));
+        }
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[1], (0, 1, 0));
+    }
+
+    #[test]
+    pub fn custom_filter() {
+        let input = "fn  main ( )";
+        let mut parser = Rust::make_parser();
+        parser.parse(input);
+        let mut pipeline = Pipeline::new().add_filter(SkipWhitespace);
+        let tokens: Vec<_> = pipeline.apply(input, parser.tokens()).collect();
+        assert_eq!(tokens, vec![
+            ("fn", Rust::Keyword), ("main", Rust::FnCall), ("( )", Rust::Punctuation),
+        ]);
+    }
+}
+
+#[cfg(test)]
+pub mod trace {
+    use xhighlight::parse::{RegexPat, Transition};
+    use xhighlight::syntax::rust::Rust;
+
+    #[test]
+    pub fn records_patterns() {
+        let mut parser = Rust::make_parser();
+        parser.set_trace(true);
+        parser.parse("fn main /* x */");
+        while parser.next_match().is_some() {}
+
+        let trace = parser.trace();
+        assert_eq!(trace.len(), 7);
+        assert_eq!(trace[0].pattern, Some(0));
+        assert_eq!(trace[0].label.as_deref(), Some("Keyword<Keyword>"));
+        assert_eq!(trace[0].transition, Some(Transition::Goto(Rust::Text)));
+        // Whitespace between matches isn't matched by a pattern
+        assert_eq!(trace[1].pattern, None);
+        assert_eq!(trace[2].label.as_deref(), Some("OptionalLA<FnCall, Identifier>"));
+        assert_eq!(trace[4].transition, Some(Transition::Goto(Rust::BlockComment)));
+        assert_eq!(trace[6].state, Rust::BlockComment);
+        assert_eq!(trace[6].transition, Some(Transition::Pop(1)));
+
+        let table = parser.trace_table();
+        assert_eq!(table.lines().count(), 8);
+        assert!(table.lines().nth(3).unwrap().contains("\"main\""));
+
+        // Starting to parse again clears the trace
+        parser.parse("");
+        assert!(parser.trace().is_empty());
+        parser.set_trace(false);
+        assert_eq!(parser.trace_table().lines().count(), 1);
+    }
+
+    #[test]
+    pub fn captures_apply_the_transition_once() {
+        let patterns = vec![
+            (RegexPat::captures(r"(a)b(c)", &[(1, Rust::Keyword), (2, Rust::Number)], Rust::Text),
+                Rust::String),
+        ];
+        let mut parser = xhighlight::parse::Parser::new(Rust::Text);
+        parser.add_matcher(Rust::Text, &patterns).set_trace(true);
+        parser.parse("abc");
+        while parser.next_match().is_some() {}
+
+        let transitions: Vec<_> = parser.trace().iter().map(|e| (e.pattern, e.transition)).collect();
+        assert_eq!(transitions, vec![
+            (Some(0), None), (Some(0), None), (Some(0), Some(Transition::Goto(Rust::String))),
+        ]);
+    }
+
+    #[test]
+    pub fn byte_input() {
+        let mut parser = Rust::make_parser();
+        parser.set_trace(true);
+        let _: Vec<_> = parser.parse_bytes(b"abcdef \xFFx").collect();
+
+        let ranges: Vec<_> = parser.trace().iter().map(|e| (e.range.clone(), e.text.as_str())).collect();
+        assert_eq!(ranges, vec![(0..6, "abcdef"), (6..7, " "), (8..9, "x")]);
+        assert!(parser.trace_table().lines().nth(3).unwrap().contains("\"x\""));
+    }
+}
+
+#[cfg(test)]
+pub mod limits {
+    use std::io::BufReader;
+    use std::time::Duration;
+    use xhighlight::parse::{Limit, Limits};
+    use xhighlight::render::{HtmlRenderer, Renderer, SharedHtmlRenderer};
+    use xhighlight::stream::TokenStream;
+    use xhighlight::syntax::rust::Rust;
+
+    #[test]
+    pub fn max_input() {
+        let mut parser = Rust::make_parser();
+        parser.set_limits(Limits { max_input: Some(5), ..Limits::default() });
+        parser.parse("fn a() {}");
+        let tokens: Vec<_> = (&mut parser).collect();
+        assert_eq!(tokens, vec![("fn a() {}", Rust::Text)]);
+        assert_eq!(parser.limit_exceeded(), Some(Limit::Input));
+
+        parser.parse("fn a");
+        assert_eq!(parser.next_match(), Some(("fn", Rust::Keyword)));
+        assert_eq!(parser.limit_exceeded(), None);
+    }
+
+    #[test]
+    pub fn max_steps() {
+        let mut parser = Rust::make_parser();
+        parser.set_limits(Limits { max_steps: Some(5), ..Limits::default() });
+        parser.parse("let a = \"b\";\nlet c = d;");
+        let tokens: Vec<_> = (&mut parser).collect();
+        assert_eq!(parser.limit_exceeded(), Some(Limit::Steps));
+
+        // The remainder is a single token of the default state
+        let (rest, hl) = *tokens.last().unwrap();
+        assert_eq!(hl, Rust::Text);
+        assert!(rest.ends_with("let c = d;"));
+        let text: String = tokens.iter().map(|(s, _)| *s).collect();
+        assert_eq!(text, "let a = \"b\";\nlet c = d;");
+    }
+
+    #[test]
+    pub fn time_limit() {
+        let mut grammar = Rust::grammar();
+        grammar.set_limits(Limits { time_limit: Some(Duration::from_secs(0)), ..Limits::default() });
+        let renderer = SharedHtmlRenderer::new(grammar).set_mapping(&super::RUST_CSS);
+        let (html, limit) = renderer.render_checked("fn a");
+        assert_eq!(html, "fn a");
+        assert_eq!(limit, Some(Limit::Time));
+
+        let mut parser = Rust::make_parser();
+        parser.set_limits(Limits { time_limit: Some(Duration::from_secs(60)), ..Limits::default() });
+        let mut renderer = HtmlRenderer::new(&mut parser).set_mapping(&super::RUST_CSS);
+        renderer.render("fn a() {}");
+        assert_eq!(renderer.limit_exceeded(), None);
+    }
+
+    #[test]
+    pub fn stream() {
+        // Every chunk would be within the limits, but the whole stream isn't
+        let input = "let a = 1;\n".repeat(100);
+        let reader = BufReader::with_capacity(7, input.as_bytes());
+        let mut parser = Rust::make_parser();
+        parser.set_limits(Limits { max_steps: Some(50), ..Limits::default() });
+        let mut stream = TokenStream::new(parser, reader).set_lookahead(16);
+        let tokens = (&mut stream).collect::<std::io::Result<Vec<_>>>().unwrap();
+        assert_eq!(stream.limit_exceeded(), Some(Limit::Steps));
+        let text: String = tokens.iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(text, input);
+        assert!(tokens[4 ..].iter().any(|(_, hl)| *hl != Rust::Text));
+        assert!(tokens.iter().rev().take(10).all(|(_, hl)| *hl == Rust::Text));
+
+        let mut parser = Rust::make_parser();
+        parser.set_limits(Limits { max_input: Some(500), ..Limits::default() });
+        let mut stream = TokenStream::new(parser, input.as_bytes()).set_lookahead(16);
+        let tokens = (&mut stream).collect::<std::io::Result<Vec<_>>>().unwrap();
+        assert_eq!(stream.limit_exceeded(), Some(Limit::Input));
+        assert_eq!(tokens.last().unwrap().1, Rust::Text);
+        assert_eq!(tokens.iter().map(|(s, _)| s.len()).sum::<usize>(), input.len());
+    }
+}
+
+#[cfg(test)]
+pub mod lines {
+    use xhighlight::syntax::rust::Rust;
+    use xhighlight::token::Line;
+
+    #[test]
+    pub fn multi_line_tokens() {
+        let input = "let s = r\"a\n\nb\";\r\n/* c\r\n*/\n";
+        let mut parser = Rust::make_parser();
+        parser.parse(input);
+        let lines: Vec<Line<Rust>> = parser.lines().collect();
+
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0].tokens.last(), Some(&("r\"a", Rust::String)));
+        assert_eq!(lines[1].tokens, vec![]);
+        assert_eq!(lines[1].range, 12 .. 12);
+        assert_eq!(lines[2].tokens, vec![("b\"", Rust::String), (";", Rust::Punctuation)]);
+        assert_eq!(lines[2].line_break, "\r\n");
+        assert_eq!(lines[3].state, Rust::Text);
+        assert_eq!(lines[3].tokens, vec![("/*", Rust::BlockComment), (" c", Rust::BlockComment)]);
+        assert_eq!(lines[4].state, Rust::BlockComment);
+        assert_eq!(lines[4].tokens, vec![("*/", Rust::BlockComment)]);
+        assert_eq!(lines[4].line_break, "\n");
+
+        // The lines and line breaks contain the whole input
+        let text: String = lines.iter()
+            .flat_map(|line| line.tokens.iter().map(|(s, _)| *s).chain(Some(line.line_break)))
+            .collect();
+        assert_eq!(text, input);
+        for (i, line) in lines.iter().enumerate() {
+            assert_eq!(line.number, i);
+            let content: String = line.tokens.iter().map(|(s, _)| *s).collect();
+            assert_eq!(&input[line.range.clone()], content);
+        }
+    }
+
+    #[test]
+    pub fn last_line() {
+        let mut parser = Rust::make_parser();
+        parser.parse("a\nb");
+        let lines: Vec<_> = parser.lines().map(|line| (line.tokens, line.line_break)).collect();
+        assert_eq!(lines, vec![
+            (vec![("a", Rust::Identifier)], "\n"),
+            (vec![("b", Rust::Identifier)], ""),
+        ]);
+
+        parser.parse("");
+        assert_eq!(parser.lines().count(), 0);
+    }
+}
+
+#[cfg(test)]
+pub mod bytes {
+    use xhighlight::parse::{next_char, ByteToken};
+    use xhighlight::render::HtmlRenderer;
+    use xhighlight::syntax::rust::Rust;
+
+    #[test]
+    pub fn invalid_sequences() {
+        // Latin-1 encoded `ä` and a truncated sequence at the end
+        let input: &[u8] = b"// a\xE4b\nlet c\xE2\x82";
+        let mut parser = Rust::make_parser();
+        let tokens: Vec<_> = parser.parse_bytes(input).collect();
+        assert_eq!(tokens, vec![
+            ByteToken::Text("// a", Rust::LineComment),
+            ByteToken::Invalid(b"\xE4"),
+            ByteToken::Text("b", Rust::Identifier),
+            ByteToken::Text("\n", Rust::Text),
+            ByteToken::Text("let", Rust::Keyword),
+            ByteToken::Text(" ", Rust::Text),
+            ByteToken::Text("c", Rust::Identifier),
+            ByteToken::Invalid(b"\xE2\x82"),
+        ]);
+
+        // Valid input is parsed as usual
+        let tokens: Vec<_> = parser.parse_bytes("fn ä()".as_bytes()).collect();
+        assert_eq!(tokens, vec![
+            ByteToken::Text("fn", Rust::Keyword), ByteToken::Text(" ", Rust::Text),
+            ByteToken::Text("ä", Rust::FnCall), ByteToken::Text("()", Rust::Punctuation),
+        ]);
+    }
+
+    #[test]
+    pub fn lossy_rendering() {
+        let input: &[u8] = b"/* \xFF\xFE */ x";
+        let mut parser = Rust::make_parser();
+        let html = HtmlRenderer::new(&mut parser)
+            .set_mapping(&super::RUST_CSS)
+            .render_bytes(input);
+        assert_eq!(html, "<span class=\"com\">/*</span><span class=\"com\"> </span>\u{FFFD}\u{FFFD}\
+            <span class=\"com\"> </span><span class=\"com\">*/</span> x");
+    }
+
+    #[test]
+    pub fn checked_helpers() {
+        let s = "aä€😀".as_bytes();
+        assert_eq!(next_char(s, 0), Some('a'));
+        assert_eq!(next_char(s, 1), Some('ä'));
+        assert_eq!(next_char(s, 2), None);
+        assert_eq!(next_char(s, 3), Some('€'));
+        assert_eq!(next_char(s, 6), Some('😀'));
+        assert_eq!(next_char(s, 10), None);
+        assert_eq!(next_char(&s[.. 8], 6), None);
+
+        let mut parser = Rust::make_parser();
+        parser.parse("äb");
+        assert!(parser.offset(1).is_err());
+        assert!(parser.offset(2).is_ok());
+        assert_eq!(parser.next_match(), Some(("b", Rust::Identifier)));
+    }
+}
+
+#[cfg(test)]
+pub mod hierarchy {
+    use xhighlight::parse::Highlight;
+    use xhighlight::render::{HtmlRenderer, Renderer};
+    use xhighlight::syntax::javascript::JS;
+    use xhighlight::syntax::rust::Rust;
+
+    #[test]
+    pub fn parents() {
+        assert_eq!(Rust::DocComment.parent(), Some(Rust::Comment));
+        assert_eq!(Rust::Comment.parent(), None);
+        assert_eq!(JS::TplInner.parent(), Some(JS::TemplateString));
+        assert_eq!(JS::TemplateString.parent(), Some(JS::String));
+    }
+
+    #[test]
+    pub fn renderer_fallback() {
+        let mut parser = Rust::make_parser();
+        let html = HtmlRenderer::new(&mut parser)
+            .set_mapping(&[(Rust::Comment, "com"), (Rust::DocComment, "doc"), (Rust::String, "str")])
+            .render("/// a\n// b\n\"\\n\"");
+        assert_eq!(html, "<span class=\"doc\">/// a</span>\n<span class=\"com\">// b</span>\n\
+            <span class=\"str\">\"</span><span class=\"str\">\\n</span><span class=\"str\">\"</span>");
+
+        // An empty class stops the fallback
+        let mut parser = JS::make_parser();
+        let html = HtmlRenderer::new(&mut parser)
+            .set_mapping(&[(JS::String, "str"), (JS::TemplateString, "")])
+            .render("'a' `b`");
+        assert_eq!(html, "<span class=\"str\">'a'</span> `b`");
+    }
+}
+
+#[cfg(test)]
+pub mod standard_kinds {
+    use xhighlight::parse::{Highlight, StandardKind};
+    use xhighlight::render::{ClassMap, HtmlRenderer, Renderer, SharedHtmlRenderer};
+    use xhighlight::syntax::javascript::JS;
+    use xhighlight::syntax::rust::Rust;
+    use xhighlight::syntax::toml::Toml;
+
+    const THEME: [(StandardKind, &str); 4] = [
+        (StandardKind::Keyword,  "kwd"),
+        (StandardKind::Function, "fun"),
+        (StandardKind::String,   "str"),
+        (StandardKind::Comment,  "com"),
+    ];
+
+    #[test]
+    pub fn kinds() {
+        assert_eq!(Rust::FnCall.standard_kind(), Some(StandardKind::Function));
+        assert_eq!(JS::FnCall.standard_kind(), Some(StandardKind::Function));
+        assert_eq!(Rust::DocComment.standard_kind(), Some(StandardKind::DocComment));
+        assert_eq!(JS::TemplateString.standard_kind(), Some(StandardKind::String));
+        assert_eq!(Toml::Name.standard_kind(), Some(StandardKind::Key));
+    }
+
+    #[test]
+    pub fn same_theme() {
+        let rust = SharedHtmlRenderer::new(Rust::grammar()).set_standard_mapping(&THEME);
+        let js = SharedHtmlRenderer::new(JS::grammar()).set_standard_mapping(&THEME);
+        assert_eq!(rust.render("let f(\"a\")"), "<span class=\"kwd\">let</span> \
+            <span class=\"fun\">f</span>(<span class=\"str\">\"</span><span class=\"str\">a</span><span class=\"str\">\"</span>)");
+        assert_eq!(js.render("var f('a')"), "<span class=\"kwd\">var</span> \
+            <span class=\"fun\">f</span>(<span class=\"str\">'a'</span>)");
+    }
+
+    #[test]
+    pub fn mapping_takes_precedence() {
+        let mut parser = Rust::make_parser();
+        let html = HtmlRenderer::new(&mut parser)
+            .set_standard_mapping(&THEME)
+            .set_mapping(&[(Rust::Comment, "c")])
+            .render("// a");
+        assert_eq!(html, "<span class=\"c\">// a</span>");
+
+        let mut classes = ClassMap::new();
+        classes.set_standard_mapping(&THEME).set_mapping(&[(Rust::String, "")]);
+        assert_eq!(classes.resolve(Rust::StringEscape), "");
+        // Doc comments don't have a class, so they get the class of their parent's kind
+        assert_eq!(classes.resolve(Rust::DocComment), "com");
+        assert_eq!(classes.resolve(Rust::Number), "");
+    }
+}
+
+#[cfg(test)]
+pub mod derive {
+    use xhighlight::parse::{Highlight, StandardKind};
+    use xhighlight::render::{HtmlRenderer, Renderer};
+    use xhighlight::syntax::rust::Rust;
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Highlight)]
+    enum MyLang {
+        Text,
+        #[highlight(class = "kwd", kind = "keyword")]
+        Keyword,
+        #[highlight(class = "com", kind = "comment")]
+        Comment,
+        #[highlight(kind = "doc_comment", parent = "Comment", name = "Doc comment")]
+        DocComment,
+    }
+
+    #[test]
+    pub fn attributes() {
+        assert_eq!(MyLang::ALL, &[MyLang::Text, MyLang::Keyword, MyLang::Comment, MyLang::DocComment]);
+        assert_eq!(MyLang::CLASSES, &[(MyLang::Keyword, "kwd"), (MyLang::Comment, "com")]);
+        assert_eq!(MyLang::Text.get_name(), "Text");
+        assert_eq!(MyLang::DocComment.get_name(), "Doc comment");
+        assert_eq!(MyLang::DocComment.parent(), Some(MyLang::Comment));
+        assert_eq!(MyLang::Comment.parent(), None);
+        assert_eq!(MyLang::Keyword.standard_kind(), Some(StandardKind::Keyword));
+        assert_eq!(MyLang::DocComment.standard_kind(), Some(StandardKind::DocComment));
+        assert_eq!(MyLang::Text.standard_kind(), None);
+    }
+
+    #[test]
+    pub fn built_in_classes() {
+        let mut parser = Rust::make_parser();
+        let html = HtmlRenderer::new(&mut parser)
+            .set_mapping(Rust::CLASSES)
+            .render("// a\nfn");
+        assert_eq!(html, "<span class=\"com\">// a</span>\n<span class=\"kwd\">fn</span>");
+        assert_eq!(Rust::ALL.len(), 20);
+    }
+}
+
+#[cfg(test)]
+pub mod grammar_macro {
+    use xhighlight::grammar;
+    use xhighlight::parse::{Highlight, RegexPat, Transition};
+    use self::Lang::*;
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Highlight)]
+    pub enum Lang {
+        Text,
+        Number,
+        Paren,
+        Str,
+    }
+
+    fn number_rules(state: Lang) -> Vec<(RegexPat<Lang>, Transition<Lang>)> {
+        vec![(RegexPat::regex(r"\d+", Number), Transition::Goto(state))]
+    }
+
+    grammar! {
+        Lang: RegexPat<Lang>, default Text, |grammar| grammar.set_max_depth(2);
+
+        Text => [
+            RegexPat::regex(r"\(", Paren) => Push(Text),
+            RegexPat::regex(r"\)", Paren) => Pop(1),
+            RegexPat::regex("\"",  Str)   => Str,
+        ] + number_rules(Text),
+        Str => [
+            RegexPat::regex("\"",  Str)   => Goto(Text),
+        ],
+    }
+
+    // A second grammar in the same module
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Highlight)]
+    pub enum Words {
+        Plain,
+        Word,
+    }
+
+    grammar! {
+        Words: RegexPat<Words>, default Plain;
+
+        Plain => [
+            RegexPat::regex(r"\w+", Words::Word) => Plain,
+        ],
+    }
+
+    #[test]
+    pub fn parses() {
+        let tokens: Vec<_> = Lang::make_parser().parse("(1 \"2\")").collect();
+        assert_eq!(tokens, vec![
+            ("(", Paren),
+            ("1", Number),
+            (" ", Text),
+            ("\"", Str),
+            ("2", Str),
+            ("\"", Str),
+            (")", Paren),
+        ]);
+        assert_eq!(Lang::grammar().validate(), Ok(()));
+    }
+
+    #[test]
+    pub fn several_grammars() {
+        let tokens: Vec<_> = Words::make_parser().parse("a 1").collect();
+        assert_eq!(tokens, vec![("a", Words::Word), (" ", Words::Plain), ("1", Words::Word)]);
+    }
+}
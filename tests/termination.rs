@@ -0,0 +1,96 @@
+//! Makes sure that the parsers terminate and don't lose any input,
+//! even on unusual or malformed input.
+
+#[macro_use]
+extern crate lazy_static;
+
+use xhighlight::parse::{Highlight, Parser, Pattern, RegexPat};
+use xhighlight::syntax::{rust::Rust, toml::Toml, javascript::JS};
+
+const CORPUS: &[&str] = &[
+    "",
+    " ",
+    "\n",
+    "\r\n\r\n",
+    "a",
+    "\"",
+    "'",
+    "`",
+    "/",
+    "/*",
+    "*/",
+    "//",
+    "#",
+    "[",
+    "]",
+    "=",
+    "${",
+    "}",
+    "\\",
+    "r#\"",
+    "\"unterminated \\\" string",
+    "`template ${ {a: 1} } string`",
+    "/* /* nested */ comment */",
+    "let x = /regex/g / 2;",
+    "[table]\nkey = [ [1, 2], \"a\" ]\n",
+    "äöü ß € 𝄞 \u{200b} \u{feff}",
+    "fn main() { println!(\"Hello, {}!\", r##\"world\"##); }",
+    "\0\u{1}\u{7f}",
+    "=====\n[[[[\n]]]]\n,,,,",
+];
+
+fn assert_terminates<'a, H, P>(parser: &mut Parser<'a, H, P>, input: &'a str)
+    where H: Highlight, P: Pattern<H>
+{
+    parser.parse(input);
+    let mut output = String::new();
+    // Every token consumes at least one byte
+    for (i, (token, _)) in parser.enumerate() {
+        assert!(i < input.len(), "Parser doesn't terminate on {:?}", input);
+        output.push_str(token);
+    }
+    assert_eq!(output, input);
+}
+
+#[test]
+pub fn built_in_grammars_terminate() {
+    for input in CORPUS {
+        let repeated = input.repeat(3);
+        for input in &[*input, repeated.as_str()] {
+            assert_terminates(&mut Rust::make_parser(), input);
+            assert_terminates(&mut Toml::make_parser(), input);
+            assert_terminates(&mut JS::make_parser(), input);
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum Lang {
+    Text,
+    Space,
+    Word,
+}
+impl Highlight for Lang {}
+
+lazy_static! {
+    static ref TEXT: Vec<(RegexPat<Lang>, Lang)> = {
+        vec![
+            (RegexPat::regex(r"\s*", Lang::Space), Lang::Word),
+            (RegexPat::regex(r"\w*", Lang::Word),  Lang::Text),
+        ]
+    };
+    static ref WORD: Vec<(RegexPat<Lang>, Lang)> = {
+        vec![ (RegexPat::shortest(r"\b|\w*", Lang::Word), Lang::Text) ]
+    };
+}
+
+#[test]
+pub fn empty_matches_terminate() {
+    let mut parser = Parser::new(Lang::Text);
+    parser.add_matcher(Lang::Text, &TEXT);
+    parser.add_matcher(Lang::Word, &WORD);
+
+    for input in CORPUS {
+        assert_terminates(&mut parser, input);
+    }
+}
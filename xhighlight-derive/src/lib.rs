@@ -0,0 +1,149 @@
+//! `#[derive(Highlight)]` for enums, re-exported as `xhighlight::parse::Highlight`.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, Ident, LitStr};
+
+
+/// The variants of `StandardKind`, with the names that are used in `kind = "..."`
+const KINDS: &[(&str, &str)] = &[
+    ("text", "Text"),
+    ("keyword", "Keyword"),
+    ("identifier", "Identifier"),
+    ("function", "Function"),
+    ("macro", "Macro"),
+    ("type", "Type"),
+    ("lifetime", "Lifetime"),
+    ("operator", "Operator"),
+    ("punctuation", "Punctuation"),
+    ("comment", "Comment"),
+    ("doc_comment", "DocComment"),
+    ("string", "String"),
+    ("escape", "Escape"),
+    ("char", "Char"),
+    ("number", "Number"),
+    ("bool", "Bool"),
+    ("constant", "Constant"),
+    ("regex", "Regex"),
+    ("annotation", "Annotation"),
+    ("key", "Key"),
+    ("section", "Section"),
+];
+
+/// The `#[highlight(...)]` attributes of a variant
+struct Variant {
+    ident: Ident,
+    class: Option<LitStr>,
+    kind: Option<Ident>,
+    name: Option<LitStr>,
+    parent: Option<Ident>,
+}
+
+#[proc_macro_derive(Highlight, attributes(highlight))]
+pub fn derive_highlight(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let data = match &input.data {
+        Data::Enum(data) => data,
+        _ => return Err(Error::new_spanned(&input.ident, "Highlight can only be derived for enums")),
+    };
+    let variants = data.variants.iter().map(|v| {
+        if !matches!(v.fields, Fields::Unit) {
+            return Err(Error::new_spanned(v, "Highlight variants can't have fields"));
+        }
+        parse_variant(v.ident.clone(), &v.attrs)
+    }).collect::<syn::Result<Vec<_>>>()?;
+
+    let ty = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let names = variants.iter().map(|v| {
+        let ident = &v.ident;
+        let name = v.name.clone().unwrap_or_else(|| LitStr::new(&ident.to_string(), ident.span()));
+        quote!(#ty::#ident => #name)
+    });
+    let parents = variants.iter().filter_map(|v| {
+        let (ident, parent) = (&v.ident, v.parent.as_ref()?);
+        Some(quote!(#ty::#ident => ::std::option::Option::Some(#ty::#parent)))
+    });
+    let kinds = variants.iter().filter_map(|v| {
+        let (ident, kind) = (&v.ident, v.kind.as_ref()?);
+        Some(quote!(#ty::#ident => ::std::option::Option::Some(::xhighlight::parse::StandardKind::#kind)))
+    });
+    let all = variants.iter().map(|v| &v.ident);
+    let classes = variants.iter().filter_map(|v| {
+        let (ident, class) = (&v.ident, v.class.as_ref()?);
+        Some(quote!((#ty::#ident, #class)))
+    });
+
+    Ok(quote! {
+        impl #impl_generics ::xhighlight::parse::Highlight for #ty #ty_generics #where_clause {
+            fn get_name(&self) -> ::std::string::String {
+                ::std::string::String::from(match self {
+                    #(#names,)*
+                })
+            }
+
+            #[allow(unreachable_patterns)]
+            fn parent(&self) -> ::std::option::Option<Self> {
+                match self {
+                    #(#parents,)*
+                    _ => ::std::option::Option::None,
+                }
+            }
+
+            #[allow(unreachable_patterns)]
+            fn standard_kind(&self) -> ::std::option::Option<::xhighlight::parse::StandardKind> {
+                match self {
+                    #(#kinds,)*
+                    _ => ::std::option::Option::None,
+                }
+            }
+        }
+
+        impl #impl_generics #ty #ty_generics #where_clause {
+            /// All highlights, in the order in which they are declared
+            pub const ALL: &'static [Self] = &[#(#ty::#all),*];
+
+            /// The CSS classes from the `#[highlight(class = "...")]` attributes,
+            /// e.g. for `HtmlRenderer::set_mapping`
+            pub const CLASSES: &'static [(Self, &'static str)] = &[#(#classes),*];
+        }
+    })
+}
+
+fn parse_variant(ident: Ident, attrs: &[syn::Attribute]) -> syn::Result<Variant> {
+    let mut variant = Variant { ident, class: None, kind: None, name: None, parent: None };
+    for attr in attrs.iter().filter(|a| a.path().is_ident("highlight")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("class") {
+                variant.class = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("name") {
+                variant.name = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("parent") {
+                let parent: LitStr = meta.value()?.parse()?;
+                variant.parent = Some(parent.parse()?);
+            } else if meta.path.is_ident("kind") {
+                let kind: LitStr = meta.value()?.parse()?;
+                let variant_name = KINDS.iter()
+                    .find(|&&(name, _)| name == kind.value())
+                    .map(|&(_, variant)| variant)
+                    .ok_or_else(|| {
+                        let names: Vec<_> = KINDS.iter().map(|&(name, _)| name).collect();
+                        Error::new_spanned(&kind, format!("unknown kind, expected one of: {}", names.join(", ")))
+                    })?;
+                variant.kind = Some(Ident::new(variant_name, kind.span()));
+            } else {
+                return Err(meta.error("expected `class`, `kind`, `name` or `parent`"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(variant)
+}